@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::{Credentials, KvsError, Request, Result};
+
+/// The access control list of `KvsServer`, which authenticates users by their tokens,
+/// and grants them permissions on the keys with some prefixes.
+///
+/// It is loaded from a JSON file like:
+///
+/// ```json
+/// {
+///     "users": [
+///         {
+///             "name": "alice",
+///             "token": "secret",
+///             "grants": [
+///                 { "prefix": "", "permission": "read" },
+///                 { "prefix": "alice/", "permission": "write" }
+///             ]
+///         }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Acl {
+    users: Arc<HashMap<String, AclUser>>,
+}
+
+/// The permission on keys.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Permits getting and watching keys.
+    Read,
+    /// Permits setting and removing keys, as well as getting them.
+    Write,
+}
+
+#[derive(Deserialize)]
+struct AclFile {
+    users: Vec<AclUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AclUser {
+    name: String,
+    token: String,
+    grants: Vec<Grant>,
+}
+
+/// A permission on the keys starting with the prefix, where an empty prefix matches every key.
+#[derive(Deserialize, Debug)]
+struct Grant {
+    prefix: String,
+    permission: Permission,
+}
+
+impl Acl {
+    /// Loads the access control list from a JSON file.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serde_json deserialization errors,
+    /// or returns `KvsError::InvalidAcl` error if a user is listed twice.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Acl> {
+        let file: AclFile = serde_json::from_slice(&fs::read(path)?)?;
+        let mut users = HashMap::new();
+        for user in file.users {
+            if users.contains_key(&user.name) {
+                return Err(KvsError::InvalidAcl(format!(
+                    "user {} is listed twice",
+                    user.name
+                )));
+            }
+            users.insert(user.name.clone(), user);
+        }
+        Ok(Acl {
+            users: Arc::new(users),
+        })
+    }
+
+    /// Authenticates the credentials of a client, and returns the name of the user.
+    ///
+    /// Returns `None`, if the credentials are missing or do not match a user.
+    pub(crate) fn authenticate(&self, credentials: Option<&Credentials>) -> Option<String> {
+        let credentials = credentials?;
+        let user = self.users.get(&credentials.user)?;
+        if constant_time_eq(user.token.as_bytes(), credentials.token.as_bytes()) {
+            Some(user.name.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Checks that the user is permitted to send the request.
+    ///
+    /// Every authenticated user is permitted to get the statistics of the server.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PermissionDenied` error if no grant of the user permits it.
+    pub(crate) fn authorize(&self, user: &str, request: &Request) -> Result<()> {
+        let (key, permission, action) = match request {
+            // The statistics of the server are not about any key.
+            Request::Info => return Ok(()),
+            Request::Get { key } => (key, Permission::Read, "read"),
+            Request::Set { key, .. } => (key, Permission::Write, "set"),
+            Request::Remove { key } => (key, Permission::Write, "remove"),
+            // Every key starting with a prefix is permitted, if the prefix is.
+            Request::Watch { key, .. } => (key, Permission::Read, "watch"),
+        };
+        let permitted = self.users.get(user).is_some_and(|user| {
+            user.grants.iter().any(|grant| {
+                key.starts_with(&grant.prefix)
+                    && (grant.permission == permission || grant.permission == Permission::Write)
+            })
+        });
+        if permitted {
+            Ok(())
+        } else {
+            Err(KvsError::PermissionDenied(format!(
+                "user {} may not {} key {}",
+                user, action, key
+            )))
+        }
+    }
+}
+
+/// Compares two byte strings in time independent of where they differ,
+/// so that a token can not be guessed byte by byte from the response time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
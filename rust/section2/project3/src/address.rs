@@ -0,0 +1,47 @@
+use std::fmt;
+use std::net;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The address of a `KvsServer`, which is either a tcp socket address,
+/// or the path of a Unix domain socket on the same host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// A tcp socket address `IP:PORT`.
+    Tcp(net::SocketAddr),
+    /// The path of a Unix domain socket, written as `unix:PATH`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<net::SocketAddr> for Address {
+    fn from(addr: net::SocketAddr) -> Self {
+        Address::Tcp(addr)
+    }
+}
+
+impl FromStr for Address {
+    type Err = net::AddrParseError;
+
+    /// Parses `unix:PATH` as a Unix domain socket, or else a tcp socket address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = s.strip_prefix("unix:") {
+                return Ok(Address::Unix(PathBuf::from(path)));
+            }
+        }
+        Ok(Address::Tcp(s.parse()?))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::net;
+
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::protocol;
+use crate::protocol::Negotiated;
+use crate::{Request, Response, Result};
+
+/// The async kvs client on tokio, which speaks the same protocol as `KvsClient`.
+pub struct AsyncKvsClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    negotiated: Negotiated,
+}
+
+impl AsyncKvsClient {
+    /// Creates a new `AsyncKvsClient`, which connects to the server and performs the handshake.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `KvsError::ProtocolMismatch` error
+    /// if the server does not support the protocol of this client.
+    pub async fn new(addr: net::SocketAddr) -> Result<AsyncKvsClient> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let negotiated = protocol::connect_async(&mut reader, &mut writer).await?;
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            negotiated,
+        })
+    }
+
+    /// Returns the protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u16 {
+        self.negotiated.version
+    }
+
+    /// Returns the capabilities supported by both the client and the server.
+    pub fn capabilities(&self) -> &[String] {
+        &self.negotiated.capabilities
+    }
+
+    /// Sends set command to the server.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        Result::from(self.send(&Request::Set { key, value }).await?)
+    }
+
+    /// Sends get command to the server.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        Result::from(self.send(&Request::Get { key }).await?)
+    }
+
+    /// Sends remove command to the server.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        Result::from(self.send(&Request::Remove { key }).await?)
+    }
+
+    /// Sends a request to the server, and receives the response.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    async fn send(&mut self, request: &Request) -> Result<Response> {
+        protocol::write_frame_async(&mut self.writer, request).await?;
+        match protocol::read_frame_async(&mut self.reader).await? {
+            Some(res) => Ok(res),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::future::{self, Future};
+use std::io;
+use std::net;
+use std::sync::atomic::AtomicUsize;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::metrics::MetricsServer;
+use crate::protocol;
+use crate::server::{handle_request, RejectGuard, REJECT_TIMEOUT, WATCH_POLL_INTERVAL};
+use crate::stats::ServerStats;
+use crate::{
+    ErrorCode, KvsEngine, KvsError, KvsServerOptions, Request, Response, Result, Subscription,
+    WatchHub, WatchedEngine,
+};
+
+/// The async kvs server on tokio, which speaks the same protocol as `KvsServer`.
+///
+/// Each connection is served by a task, so that idle connections are cheap.
+/// Requests are dealt with by handles of the engine on the blocking thread pool,
+/// since the engine does blocking I/O.
+///
+/// Every write through the engine is published to the clients watching its key,
+/// including the writes through the handle returned by `engine`.
+pub struct AsyncKvsServer<T: KvsEngine + Clone + Send + 'static> {
+    addr: net::SocketAddr,
+    engine: WatchedEngine<T>,
+    options: KvsServerOptions,
+    shutdown: AsyncShutdownHandle,
+    stats: Arc<ServerStats>,
+    rejecting: Arc<AtomicUsize>,
+}
+
+/// A handle to shut down an `AsyncKvsServer` from another thread or task,
+/// e.g. a signal handler.
+#[derive(Clone)]
+pub struct AsyncShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl AsyncShutdownHandle {
+    fn new() -> AsyncShutdownHandle {
+        AsyncShutdownHandle {
+            sender: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Shuts down the server.
+    ///
+    /// The server stops accepting connections, and stops reading requests from its connections.
+    /// `AsyncKvsServer::start` returns after the requests being served are responded,
+    /// and the engine is flushed.
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Returns whether the server is shut down.
+    pub fn is_shutdown(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Returns a receiver, with which `shut_down` completes once the server is shut down.
+    fn subscribe(&self) -> watch::Receiver<bool> {
+        self.sender.subscribe()
+    }
+}
+
+/// Decrements the number of connections on drop.
+struct ConnectionGuard {
+    stats: Arc<ServerStats>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.disconnect();
+    }
+}
+
+impl<T: KvsEngine + Clone + Send + 'static> AsyncKvsServer<T> {
+    /// Creates a new `AsyncKvsServer` with default options.
+    pub fn new(addr: net::SocketAddr, engine: T) -> AsyncKvsServer<T> {
+        Self::with_options(addr, engine, KvsServerOptions::new())
+    }
+
+    /// Creates a new `AsyncKvsServer` with the given options.
+    ///
+    /// TLS and access control are not supported, so `start` fails if either is set.
+    pub fn with_options(
+        addr: net::SocketAddr,
+        engine: T,
+        options: KvsServerOptions,
+    ) -> AsyncKvsServer<T> {
+        AsyncKvsServer {
+            addr,
+            engine: WatchedEngine::new(engine, WatchHub::new()),
+            options,
+            shutdown: AsyncShutdownHandle::new(),
+            stats: Arc::default(),
+            rejecting: Arc::default(),
+        }
+    }
+
+    /// Returns a handle to shut down this server.
+    pub fn shutdown_handle(&self) -> AsyncShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns a handle of the engine, whose writes are published to the watchers of this server.
+    ///
+    /// Other servers sharing the engine, e.g. `RespServer`, should write through this handle,
+    /// so that their writes are watched as well.
+    pub fn engine(&self) -> WatchedEngine<T> {
+        self.engine.clone()
+    }
+
+    /// Creates a server of the metrics of this server and its engine in the Prometheus text format,
+    /// which listens on the given addr once it is started.
+    pub fn metrics_server(&self, addr: net::SocketAddr) -> MetricsServer<WatchedEngine<T>> {
+        MetricsServer::new(addr, self.engine(), self.stats.clone())
+    }
+
+    /// Creates tcp server to listen on the given addr.
+    ///
+    /// It accepts connections in the main loop, and spawns a task to serve each connection
+    /// until it is closed, or to reject it if there are too many connections.
+    /// It must be called within a tokio runtime.
+    ///
+    /// It returns after it is shut down by an `AsyncShutdownHandle`, the requests being served
+    /// are responded, and the engine is flushed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of binding the address, or errors of flushing the engine.
+    ///
+    /// It returns an I/O error of `InvalidInput` kind if TLS or access control is set.
+    pub async fn start(&self) -> Result<()> {
+        if self.options.tls.is_some() || self.options.acl.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "async server does not support TLS or access control",
+            )
+            .into());
+        }
+
+        let listener = TcpListener::bind(self.addr).await?;
+        self.stats.start();
+        let mut shutdown = self.shutdown.subscribe();
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = shut_down(&mut shutdown) => break,
+                // Finished connections are reaped, so that the set does not grow.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let options = self.options.clone();
+                        if self.stats.connect() >= options.max_connections {
+                            self.stats.reject();
+                            match RejectGuard::new(&self.rejecting) {
+                                Some(guard) => {
+                                    connections.spawn(async move {
+                                        let _guard = guard;
+                                        if let Err(e) = reject(stream).await {
+                                            debug!("Connection error while rejecting: {}", e);
+                                        }
+                                    });
+                                }
+                                None => debug!(
+                                    "Close excess connection: too many connections being rejected"
+                                ),
+                            }
+                        } else {
+                            let guard = ConnectionGuard {
+                                stats: self.stats.clone(),
+                            };
+                            let engine = self.engine.clone();
+                            let shutdown = self.shutdown.subscribe();
+                            connections.spawn(async move {
+                                let stats = guard.stats.clone();
+                                let _guard = guard;
+                                if let Err(e) = serve(engine, stream, &options, stats, shutdown).await {
+                                    warn!("Connection error: {}", e);
+                                }
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Tcp accept error: {}", e);
+                    }
+                },
+            }
+        }
+
+        drop(listener);
+        while connections.join_next().await.is_some() {}
+        let mut engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || engine.flush())
+            .await
+            .map_err(io::Error::other)??;
+        info!("Server is shut down");
+        Ok(())
+    }
+}
+
+/// Rejects a connection within `REJECT_TIMEOUT`, because there are too many connections.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization and deserialization errors.
+async fn reject(stream: TcpStream) -> Result<()> {
+    warn!(
+        "Reject connection from {}: server busy",
+        stream.peer_addr()?
+    );
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    with_timeout(
+        Some(REJECT_TIMEOUT),
+        protocol::reject_busy_async(&mut reader, &mut writer),
+    )
+    .await
+}
+
+/// Serves a connection with the limits of the options, like the `serve` of `KvsServer`.
+///
+/// After the handshake, it reads framed `Request`s from the tcp stream one by one.
+/// Then use a handle of the engine to deal with each request and responses data or errors.
+///
+/// A `Request::Watch` turns the rest of the connection into a stream of `WatchEvent`s.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization and deserialization errors.
+async fn serve<T: KvsEngine + Clone + Send + 'static>(
+    engine: WatchedEngine<T>,
+    stream: TcpStream,
+    options: &KvsServerOptions,
+    stats: Arc<ServerStats>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let accepted = tokio::select! {
+        _ = shut_down(&mut shutdown) => return Ok(()),
+        accepted = with_timeout(
+            options.request_timeout,
+            protocol::accept_async(&mut reader, &mut writer),
+        ) => accepted?,
+    };
+    let negotiated = match accepted {
+        Some(negotiated) => negotiated,
+        None => {
+            warn!("Reject connection from {}", peer_addr);
+            return Ok(());
+        }
+    };
+    debug!(
+        "Accept connection from {} with protocol version {} and capabilities {:?}",
+        peer_addr, negotiated.version, negotiated.capabilities
+    );
+
+    loop {
+        // Waiting for the next request stops at shutdown or the idle timeout,
+        // but a request being read is served.
+        tokio::select! {
+            _ = shut_down(&mut shutdown) => return Ok(()),
+            _ = sleep(options.idle_timeout) => {
+                debug!("Close idle connection from {}", peer_addr);
+                return Ok(());
+            }
+            buffered = reader.fill_buf() => {
+                if buffered?.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let len = match with_timeout(
+            options.request_timeout,
+            protocol::read_frame_len_async(&mut reader),
+        )
+        .await?
+        {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        if len > options.max_request_size as usize {
+            let res = Response::Err {
+                code: ErrorCode::RequestTooLarge,
+                message: format!(
+                    "request of {} bytes exceeds {} bytes",
+                    len, options.max_request_size
+                ),
+            };
+            stats.record_response(&res);
+            write_response(&mut writer, &res, options).await?;
+            warn!("Close connection from {}: request too large", peer_addr);
+            // Drain the request before closing, so that the response is not lost by a reset.
+            writer.shutdown().await?;
+            with_timeout(options.request_timeout, async {
+                tokio::io::copy(&mut (&mut reader).take(len as u64), &mut tokio::io::sink())
+                    .await?;
+                Ok(())
+            })
+            .await?;
+            return Ok(());
+        }
+
+        let request = match with_timeout(
+            options.request_timeout,
+            protocol::read_frame_payload_async::<Request>(&mut reader, len),
+        )
+        .await
+        {
+            Ok(request) => request,
+            // The frame is skipped as a whole, so the following ones can still be read.
+            Err(e @ KvsError::Bincode(_)) => {
+                let res = Response::new_error(&e);
+                stats.record_response(&res);
+                write_response(&mut writer, &res, options).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Receive from {} with {:?}", peer_addr, request);
+
+        let started = Instant::now();
+        let name = request.name();
+        let res = match request {
+            Request::Watch { key, prefix } => {
+                stats.record_request(name, started.elapsed(), &Response::new_success(None));
+                debug!(
+                    "Watch {} {} for {}",
+                    if prefix { "prefix" } else { "key" },
+                    key,
+                    peer_addr
+                );
+                let events = engine.hub().subscribe(key, prefix);
+                let peer_addr = peer_addr.to_string();
+                return watch(
+                    &mut reader,
+                    &mut writer,
+                    events,
+                    &peer_addr,
+                    options,
+                    &mut shutdown,
+                )
+                .await;
+            }
+            request => {
+                let mut engine = engine.clone();
+                let stats = stats.clone();
+                tokio::task::spawn_blocking(move || match request {
+                    Request::Info => match stats.info(&mut engine) {
+                        Ok(info) => Response::Info(info),
+                        Err(e) => Response::new_error(&e),
+                    },
+                    request => handle_request(&mut engine, request),
+                })
+                .await
+                .map_err(io::Error::other)?
+            }
+        };
+        stats.record_request(name, started.elapsed(), &res);
+        write_response(&mut writer, &res, options).await?;
+    }
+}
+
+/// Sends the watch events to a client until it closes the connection or sends anything else,
+/// like the `watch` of `KvsServer`.
+///
+/// The connection is closed if the client lags too far behind, or if the server is shut down.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization errors.
+async fn watch(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    events: Subscription,
+    peer_addr: &str,
+    options: &KvsServerOptions,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<()> {
+    write_response(&mut *writer, &Response::new_success(None), options).await?;
+
+    // The events are polled, since the hub sends them to blocking channels.
+    let mut poll = tokio::time::interval(WATCH_POLL_INTERVAL);
+    let mut byte = [0; 1];
+    loop {
+        tokio::select! {
+            _ = shut_down(shutdown) => return Ok(()),
+            _ = reader.read(&mut byte) => {
+                debug!("Stop watching for {}", peer_addr);
+                return Ok(());
+            }
+            _ = poll.tick() => loop {
+                match events.try_recv() {
+                    Ok(event) => write_response(&mut *writer, &event, options).await?,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        warn!("Close connection from {}: watcher lags behind", peer_addr);
+                        return Ok(());
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Writes a response, or a watch event, as a frame within the request timeout.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization errors.
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    msg: &impl serde::Serialize,
+    options: &KvsServerOptions,
+) -> Result<()> {
+    with_timeout(
+        options.request_timeout,
+        protocol::write_frame_async(writer, msg),
+    )
+    .await
+}
+
+/// Runs the future within the timeout, or until it completes if the timeout is `None`.
+///
+/// # Errors
+///
+/// It propagates errors of the future, or returns an I/O error of `TimedOut` kind
+/// if it times out.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+        None => future.await,
+    }
+}
+
+/// Completes after the duration, or never if it is `None`.
+async fn sleep(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => future::pending().await,
+    }
+}
+
+/// Completes once the server is shut down.
+async fn shut_down(shutdown: &mut watch::Receiver<bool>) {
+    // An error means every handle is dropped along with the server, which is gone as well.
+    let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+}
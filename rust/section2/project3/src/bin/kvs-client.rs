@@ -1,9 +1,12 @@
-use std::net;
+use std::path::PathBuf;
 use std::process;
 
 use structopt::StructOpt;
 
-use kvs::{KvsClient, KvsError, Result};
+use kvs::{
+    Address, Credentials, KvsClient, KvsClientBuilder, Result, ServerInfo, TlsClientConfig,
+    WatchEvent,
+};
 
 #[derive(Debug, StructOpt)]
 enum Config {
@@ -13,70 +16,197 @@ enum Config {
         key: String,
         #[structopt(help = "The string value of the key", name = "VALUE")]
         value: String,
-        #[structopt(
-            long,
-            help = "Set the server address",
-            value_name = "IP:PORT",
-            default_value = "127.0.0.1:4000",
-            parse(try_from_str)
-        )]
-        addr: net::SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionConfig,
     },
     #[structopt(about = "Get the string value of a given string key")]
     Get {
         #[structopt(help = "A string key", name = "KEY")]
         key: String,
-        #[structopt(
-            long,
-            help = "Set the server address",
-            value_name = "IP:PORT",
-            default_value = "127.0.0.1:4000",
-            parse(try_from_str)
-        )]
-        addr: net::SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionConfig,
     },
     #[structopt(about = "Remove a given key")]
     Rm {
         #[structopt(help = "A string key", name = "KEY")]
         key: String,
-        #[structopt(
-            long,
-            help = "Set the server address",
-            value_name = "IP:PORT",
-            default_value = "127.0.0.1:4000",
-            parse(try_from_str)
-        )]
-        addr: net::SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionConfig,
     },
+    #[structopt(about = "Print the statistics of the server")]
+    Info {
+        #[structopt(flatten)]
+        conn: ConnectionConfig,
+    },
+    #[structopt(about = "Print the changes of a given key as they happen")]
+    Watch {
+        #[structopt(help = "A string key", name = "KEY")]
+        key: String,
+        #[structopt(long, help = "Watch every key starting with KEY")]
+        prefix: bool,
+        #[structopt(flatten)]
+        conn: ConnectionConfig,
+    },
+}
+
+/// The options of connecting to the server, which are shared by all commands.
+#[derive(Debug, StructOpt)]
+struct ConnectionConfig {
+    #[structopt(
+        long,
+        help = "Set the server address, or unix:PATH of a Unix domain socket",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        parse(try_from_str)
+    )]
+    addr: Address,
+    /// PEM file of the CA certificates which issue the server certificate, which enables TLS.
+    #[structopt(
+        long,
+        help = "Connect over TLS, trusting the CA certificates in the PEM file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    tls_ca: Option<PathBuf>,
+    /// PEM file of the certificate chain of the client for mutual TLS.
+    #[structopt(
+        long,
+        help = "Authenticate with the client certificate chain in the PEM file",
+        value_name = "PATH",
+        requires_all = &["tls-ca", "tls-key"],
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of the client for mutual TLS.
+    #[structopt(
+        long,
+        help = "Set the private key in the PEM file of the client certificate",
+        value_name = "PATH",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    /// The name which the server certificate must be valid for, the server IP address by default.
+    #[structopt(
+        long,
+        help = "Set the name which the server certificate is verified for",
+        value_name = "NAME",
+        requires = "tls-ca"
+    )]
+    tls_server_name: Option<String>,
+    /// The name of the user, which a server with an access control list requires.
+    #[structopt(
+        long,
+        help = "Authenticate as the user",
+        value_name = "NAME",
+        requires = "token"
+    )]
+    user: Option<String>,
+    /// The token of the user, which is better given by the environment variable.
+    #[structopt(
+        long,
+        help = "Set the token or password of the user",
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+}
+
+impl ConnectionConfig {
+    /// Connects to the server, over TLS if the CA certificates are given,
+    /// and as the user if the credentials are given.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of loading the TLS configuration and of `KvsClientBuilder::build`.
+    fn connect(&self) -> Result<KvsClient> {
+        let tls = match &self.tls_ca {
+            Some(ca) => {
+                let client_cert_and_key = match (&self.tls_cert, &self.tls_key) {
+                    (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+                    _ => None,
+                };
+                let tls = TlsClientConfig::from_pem_files(ca, client_cert_and_key)?;
+                match &self.tls_server_name {
+                    Some(server_name) => Some(tls.server_name(server_name)?),
+                    None => Some(tls),
+                }
+            }
+            None => None,
+        };
+        let credentials = match (&self.user, &self.token) {
+            (Some(user), Some(token)) => Some(Credentials::new(user.clone(), token.clone())),
+            _ => None,
+        };
+        KvsClientBuilder::new(self.addr.clone())
+            .tls(tls)
+            .credentials(credentials)
+            .build()
+    }
 }
 
-fn main() -> Result<()> {
+fn main() {
     let config: Config = Config::from_args();
 
+    if let Err(e) = run(config) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(config: Config) -> Result<()> {
     match config {
-        Config::Set { key, value, addr } => {
-            let mut client = KvsClient::new(addr)?;
+        Config::Set { key, value, conn } => {
+            let mut client = conn.connect()?;
             client.set(key, value)?;
         }
-        Config::Get { key, addr } => {
-            let mut client = KvsClient::new(addr)?;
+        Config::Get { key, conn } => {
+            let mut client = conn.connect()?;
             match client.get(key)? {
                 Some(value) => println!("{}", value),
                 None => println!("Key not found"),
             }
         }
-        Config::Rm { key, addr } => {
-            let mut client = KvsClient::new(addr)?;
-            match client.remove(key) {
-                Ok(()) => {}
-                Err(KvsError::RemoteError(err_msg)) => {
-                    eprintln!("{}", err_msg);
-                    process::exit(1);
+        Config::Rm { key, conn } => {
+            let mut client = conn.connect()?;
+            client.remove(key)?;
+        }
+        Config::Info { conn } => print_info(&conn.connect()?.info()?),
+        Config::Watch { key, prefix, conn } => {
+            for event in conn.connect()?.watch(key, prefix)? {
+                match event? {
+                    WatchEvent::Set { key, value } => println!("set {} {}", key, value),
+                    WatchEvent::Remove { key } => println!("rm {}", key),
                 }
-                Err(e) => return Err(e),
             }
         }
     }
 
     Ok(())
 }
+
+/// Prints the statistics one per line as `name: value`,
+/// skipping those the engine does not track.
+fn print_info(info: &ServerInfo) {
+    println!("uptime_seconds: {}", info.uptime.as_secs());
+    println!("connected_clients: {}", info.connections);
+    println!("engine: {}", info.engine.name);
+    println!("keys: {}", info.engine.keys);
+    println!("disk_size: {}", info.engine.disk_size);
+    if let Some(uncompacted_bytes) = info.engine.uncompacted_bytes {
+        println!("uncompacted_bytes: {}", uncompacted_bytes);
+    }
+    if let Some(log_files) = info.engine.log_files {
+        println!("log_files: {}", log_files);
+    }
+    if let Some(compactions) = info.engine.compactions {
+        println!("compactions: {}", compactions);
+    }
+    for (request, count) in &info.requests {
+        println!("requests_{}: {}", request, count);
+    }
+    for (code, count) in &info.errors {
+        println!("errors_{:?}: {}", code, count);
+    }
+}
@@ -1,25 +1,60 @@
+use std::env;
 use std::fs;
+use std::io;
 use std::net;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
 use clap::arg_enum;
 use structopt::StructOpt;
 
-use kvs::{KvStore, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
-use log::{info, warn, LevelFilter};
+use kvs::{
+    Acl, Address, AsyncKvsServer, Cipher, Encryption, HttpServer, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, KvsServer, KvsServerOptions, MetricsServer, RespServer, Result,
+    ShutdownHandle, SledKvsEngine, TlsServerConfig,
+};
+use log::{error, info, warn, LevelFilter};
 use std::env::current_dir;
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Start the server")]
 struct Config {
-    /// Valid socket address `IP:PORT`, where IP address is either v4 or v6.
+    /// Valid socket address `IP:PORT`, where IP address is either v4 or v6,
+    /// or `unix:PATH` of a Unix domain socket.
     #[structopt(
         long,
-        help = "Sets the server address",
+        help = "Sets the server address, or unix:PATH to listen on a Unix domain socket",
         value_name = "IP:PORT",
         default_value = "127.0.0.1:4000",
         parse(try_from_str)
     )]
-    addr: net::SocketAddr,
+    addr: Address,
+    /// Valid socket address `IP:PORT` of the RESP listener, which is disabled by default.
+    #[structopt(
+        long,
+        help = "Serves the RESP (Redis) protocol on the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<net::SocketAddr>,
+    /// Valid socket address `IP:PORT` of the HTTP listener, which is disabled by default.
+    #[structopt(
+        long,
+        help = "Serves the HTTP/JSON gateway on the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<net::SocketAddr>,
+    /// Valid socket address `IP:PORT` of the Prometheus metrics listener, which is disabled
+    /// by default.
+    #[structopt(
+        long,
+        help = "Serves Prometheus metrics at /metrics on the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<net::SocketAddr>,
     /// Valid engine name, either "kvs" or "slde".
     #[structopt(
         long,
@@ -29,6 +64,144 @@ struct Config {
         case_insensitive = true,
     )]
     engine: Option<EngineType>,
+    /// File containing the encryption key in 64 hex digits.
+    #[structopt(
+        long,
+        help = "Encrypts the log files with the key in the file",
+        value_name = "PATH",
+        conflicts_with = "encryption-key-env",
+        parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+    /// Environment variable containing the encryption key in 64 hex digits.
+    #[structopt(
+        long,
+        help = "Encrypts the log files with the key in the environment variable",
+        value_name = "NAME"
+    )]
+    encryption_key_env: Option<String>,
+    /// Valid cipher name, either "aes-256-gcm" or "chacha20-poly1305".
+    #[structopt(
+        long,
+        help = "Sets the cipher of encryption",
+        value_name = "CIPHER",
+        default_value = "aes-256-gcm",
+        possible_values = &["aes-256-gcm", "chacha20-poly1305"]
+    )]
+    cipher: Cipher,
+    /// The maximum number of connections served at the same time.
+    #[structopt(
+        long,
+        help = "Sets the maximum number of connections",
+        value_name = "N",
+        default_value = "1024"
+    )]
+    max_connections: usize,
+    /// Seconds after which a connection without requests is closed, 0 for never.
+    #[structopt(
+        long,
+        help = "Sets the idle timeout in seconds of connections",
+        value_name = "SECONDS",
+        default_value = "300"
+    )]
+    idle_timeout: u64,
+    /// Seconds to wait for the rest of a request or its response to be written, 0 for forever.
+    #[structopt(
+        long,
+        help = "Sets the timeout in seconds of reading a request and writing its response",
+        value_name = "SECONDS",
+        default_value = "30"
+    )]
+    request_timeout: u64,
+    /// The maximum size in bytes of a request.
+    #[structopt(
+        long,
+        help = "Sets the maximum size in bytes of a request",
+        value_name = "BYTES",
+        default_value = "67108864"
+    )]
+    max_request_size: u32,
+    /// PEM file of the certificate chain of the server, which enables TLS.
+    #[structopt(
+        long,
+        help = "Serves TLS with the certificate chain in the PEM file",
+        value_name = "PATH",
+        requires = "tls-key",
+        conflicts_with_all = &["async-mode", "resp-addr", "http-addr", "metrics-addr"],
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    /// PEM file of the private key of the server.
+    #[structopt(
+        long,
+        help = "Sets the private key in the PEM file for TLS",
+        value_name = "PATH",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    /// PEM file of the CA certificates which issue client certificates, which enables mutual TLS.
+    #[structopt(
+        long,
+        help = "Requires client certificates issued by the CA certificates in the PEM file",
+        value_name = "PATH",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+    /// JSON file of the access control list, which requires clients to authenticate.
+    #[structopt(
+        long,
+        help = "Authenticates clients and authorizes requests by the access control list in the file",
+        value_name = "PATH",
+        conflicts_with_all = &["async-mode", "resp-addr", "http-addr", "metrics-addr"],
+        parse(from_os_str)
+    )]
+    acl_file: Option<PathBuf>,
+    /// Whether the connections are served by tasks on tokio instead of the main thread.
+    #[structopt(
+        long = "async",
+        help = "Serves the connections asynchronously on tokio"
+    )]
+    async_mode: bool,
+    /// Whether the data directory is never modified.
+    #[structopt(long, help = "Serves the data directory in read-only mode")]
+    read_only: bool,
+}
+
+impl Config {
+    /// Reads the encryption key from the file or the environment variable, if one is given.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `InvalidEncryptionKey` error
+    /// if the key is invalid or the environment variable is not set.
+    fn encryption(&self) -> Result<Option<Encryption>> {
+        let hex_key = if let Some(path) = &self.encryption_key_file {
+            fs::read_to_string(path)?
+        } else if let Some(name) = &self.encryption_key_env {
+            env::var(name).map_err(|_| KvsError::InvalidEncryptionKey)?
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Encryption::from_hex(self.cipher, &hex_key)?))
+    }
+
+    /// Loads the TLS configuration, if the certificate and the key are given.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` error if a file can not be read or parsed.
+    fn tls(&self) -> Result<Option<TlsServerConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsServerConfig::from_pem_files(
+                cert,
+                key,
+                self.tls_client_ca.as_deref(),
+            )?)),
+            _ => Ok(None),
+        }
+    }
 }
 
 arg_enum! {
@@ -112,25 +285,229 @@ fn main() -> Result<()> {
         .filter_level(LevelFilter::Debug)
         .init();
 
-    let config: Config = Config::from_args();
-    let engine = EngineType::new(config.engine)?;
-    engine.dump_config()?;
+    let mut config: Config = Config::from_args();
+    let encryption = config.encryption()?;
+    let tls = config.tls()?;
+    let acl = config.acl_file.as_ref().map(Acl::from_file).transpose()?;
+    let engine = EngineType::new(config.engine.take())?;
+    if engine == EngineType::sled && encryption.is_some() {
+        return Err(KvsError::EncryptionUnsupported);
+    }
+    // The data directory is never modified in read-only mode.
+    if !config.read_only {
+        engine.dump_config()?;
+    }
 
     info!("Version: {}", env!("CARGO_PKG_VERSION"));
     info!("Storage Engine: {}", engine);
     info!("Socket Address: {}", config.addr);
+    if let Some(resp_addr) = config.resp_addr {
+        info!("RESP Socket Address: {}", resp_addr);
+    }
+    if let Some(http_addr) = config.http_addr {
+        info!("HTTP Socket Address: {}", http_addr);
+    }
+    if let Some(metrics_addr) = config.metrics_addr {
+        info!("Metrics Socket Address: {}", metrics_addr);
+    }
+    if config.async_mode {
+        info!("Async mode");
+    }
+    if tls.is_some() {
+        if config.tls_client_ca.is_some() {
+            info!("Mutual TLS mode");
+        } else {
+            info!("TLS mode");
+        }
+    }
+    if config.read_only {
+        info!("Read-only mode");
+    }
+    if acl.is_some() {
+        info!("Access control mode");
+        if tls.is_none() {
+            warn!("Access control without TLS sends the tokens of clients in plaintext");
+        }
+    }
+    if let Some(encryption) = &encryption {
+        info!("Encryption: {}", encryption.cipher());
+    }
 
     match engine {
-        EngineType::kvs => start_server(config.addr, KvStore::open(current_dir()?)?),
+        EngineType::kvs => {
+            let options = KvStoreOptions::new()
+                .encryption(encryption)
+                .read_only(config.read_only);
+            start_server(
+                &config,
+                tls,
+                acl,
+                KvStore::open_with_options(current_dir()?, options)?,
+            )
+        }
         EngineType::sled => {
-            start_server(config.addr, SledKvsEngine::open(current_dir()?.as_path())?)
+            let path = current_dir()?;
+            let engine = if config.read_only {
+                SledKvsEngine::open_read_only(&path)?
+            } else {
+                SledKvsEngine::open(&path)?
+            };
+            start_server(&config, tls, acl, engine)
         }
     }
 }
 
-fn start_server(addr: net::SocketAddr, engine: impl KvsEngine) -> Result<()> {
-    let mut server = KvsServer::new(addr, engine);
-    server.start()?;
+fn start_server(
+    config: &Config,
+    tls: Option<TlsServerConfig>,
+    acl: Option<Acl>,
+    engine: impl KvsEngine + Clone + Send + 'static,
+) -> Result<()> {
+    let options = KvsServerOptions::new()
+        .max_connections(config.max_connections)
+        .idle_timeout(seconds(config.idle_timeout))
+        .request_timeout(seconds(config.request_timeout))
+        .max_request_size(config.max_request_size)
+        .tls(tls)
+        .acl(acl);
+    if config.async_mode {
+        // TLS and access control are refused along with async mode by the arguments.
+        let addr = match &config.addr {
+            Address::Tcp(addr) => *addr,
+            #[cfg(unix)]
+            Address::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "async mode serves tcp addresses only",
+                )
+                .into())
+            }
+        };
+        let server = AsyncKvsServer::with_options(addr, engine, options);
+        // The threaded RESP, HTTP and metrics servers are shut down by a handle of their own.
+        let side_shutdown = ShutdownHandle::default();
+        // The writes from RESP and HTTP are published to the watchers of the server as well.
+        let mut side_servers = start_side_servers(config, &server.engine(), &side_shutdown);
+        if let Some(metrics_addr) = config.metrics_addr {
+            side_servers.push(start_metrics_server(
+                config,
+                server.metrics_server(metrics_addr),
+                &side_shutdown,
+            ));
+        }
+
+        let shutdown = server.shutdown_handle();
+        let ctrlc_side_shutdown = side_shutdown.clone();
+        ctrlc::set_handler(move || {
+            info!("Shutting down");
+            shutdown.shutdown();
+            ctrlc_side_shutdown.shutdown();
+        })
+        .map_err(io::Error::other)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        let res = runtime.block_on(server.start());
+        side_shutdown.shutdown();
+        res?;
+        join_side_servers(side_servers, server.engine())?;
+    } else {
+        let mut server = KvsServer::with_options(config.addr.clone(), engine, options);
+        let shutdown = server.shutdown_handle();
+        // The writes from RESP and HTTP are published to the watchers of the server as well.
+        let mut side_servers = start_side_servers(config, &server.engine(), &shutdown);
+        if let Some(metrics_addr) = config.metrics_addr {
+            side_servers.push(start_metrics_server(
+                config,
+                server.metrics_server(metrics_addr),
+                &shutdown,
+            ));
+        }
+
+        let ctrlc_shutdown = shutdown.clone();
+        ctrlc::set_handler(move || {
+            info!("Shutting down");
+            ctrlc_shutdown.shutdown();
+        })
+        .map_err(io::Error::other)?;
+        let res = server.start();
+        shutdown.shutdown();
+        res?;
+        join_side_servers(side_servers, server.engine())?;
+    }
 
     Ok(())
 }
+
+/// Waits for the RESP, HTTP and metrics servers to respond the requests being served
+/// after shutdown, and then flushes the engine with their writes.
+///
+/// # Errors
+///
+/// It propagates errors of flushing the engine.
+fn join_side_servers(
+    side_servers: Vec<thread::JoinHandle<()>>,
+    mut engine: impl KvsEngine,
+) -> Result<()> {
+    for side_server in side_servers {
+        let _ = side_server.join();
+    }
+    engine.flush()
+}
+
+/// Starts the metrics server in a thread.
+fn start_metrics_server(
+    config: &Config,
+    metrics_server: MetricsServer<impl KvsEngine + Clone + Send + 'static>,
+    shutdown: &ShutdownHandle,
+) -> thread::JoinHandle<()> {
+    let mut metrics_server = metrics_server
+        .request_timeout(seconds(config.request_timeout))
+        .shutdown(shutdown.clone());
+    thread::spawn(move || {
+        if let Err(e) = metrics_server.start() {
+            error!("Metrics server error: {}", e);
+        }
+    })
+}
+
+/// Starts the RESP and HTTP servers if their addresses are given,
+/// each in a thread with a handle of the engine.
+fn start_side_servers(
+    config: &Config,
+    engine: &(impl KvsEngine + Clone + Send + 'static),
+    shutdown: &ShutdownHandle,
+) -> Vec<thread::JoinHandle<()>> {
+    let mut side_servers = Vec::new();
+    if let Some(resp_addr) = config.resp_addr {
+        // The RESP listener shares the engine through a handle of it.
+        let mut resp_server = RespServer::new(resp_addr, engine.clone())
+            .idle_timeout(seconds(config.idle_timeout))
+            .request_timeout(seconds(config.request_timeout))
+            .shutdown(shutdown.clone());
+        side_servers.push(thread::spawn(move || {
+            if let Err(e) = resp_server.start() {
+                error!("RESP server error: {}", e);
+            }
+        }));
+    }
+
+    if let Some(http_addr) = config.http_addr {
+        let mut http_server = HttpServer::new(http_addr, engine.clone())
+            .request_timeout(seconds(config.request_timeout))
+            .shutdown(shutdown.clone());
+        side_servers.push(thread::spawn(move || {
+            if let Err(e) = http_server.start() {
+                error!("HTTP server error: {}", e);
+            }
+        }));
+    }
+    side_servers
+}
+
+/// Converts seconds into a timeout, where 0 means no timeout.
+fn seconds(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
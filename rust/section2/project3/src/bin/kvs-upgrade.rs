@@ -0,0 +1,77 @@
+use std::env::{self, current_dir};
+use std::fs;
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use kvs::{Cipher, Encryption, KvStore, KvStoreOptions, KvsError, Result};
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Upgrade a stopped kvs data directory from the JSON format")]
+struct Config {
+    /// The data directory, which is the current directory by default.
+    #[structopt(
+        help = "Sets the data directory",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    path: Option<PathBuf>,
+    /// File containing the encryption key in 64 hex digits.
+    #[structopt(
+        long,
+        help = "Encrypts the upgraded log files with the key in the file",
+        value_name = "PATH",
+        conflicts_with = "encryption-key-env",
+        parse(from_os_str)
+    )]
+    encryption_key_file: Option<PathBuf>,
+    /// Environment variable containing the encryption key in 64 hex digits.
+    #[structopt(
+        long,
+        help = "Encrypts the upgraded log files with the key in the environment variable",
+        value_name = "NAME"
+    )]
+    encryption_key_env: Option<String>,
+    /// Valid cipher name, either "aes-256-gcm" or "chacha20-poly1305".
+    #[structopt(
+        long,
+        help = "Sets the cipher of encryption",
+        value_name = "CIPHER",
+        default_value = "aes-256-gcm",
+        possible_values = &["aes-256-gcm", "chacha20-poly1305"]
+    )]
+    cipher: Cipher,
+}
+
+impl Config {
+    /// Reads the encryption key from the file or the environment variable, if one is given.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `InvalidEncryptionKey` error
+    /// if the key is invalid or the environment variable is not set.
+    fn encryption(&self) -> Result<Option<Encryption>> {
+        let hex_key = if let Some(path) = &self.encryption_key_file {
+            fs::read_to_string(path)?
+        } else if let Some(name) = &self.encryption_key_env {
+            env::var(name).map_err(|_| KvsError::InvalidEncryptionKey)?
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Encryption::from_hex(self.cipher, &hex_key)?))
+    }
+}
+
+fn main() -> Result<()> {
+    let config: Config = Config::from_args();
+    let options = KvStoreOptions::new().encryption(config.encryption()?);
+    let path = match config.path {
+        Some(path) => path,
+        None => current_dir()?,
+    };
+
+    KvStore::upgrade(&path, options)?;
+    println!("{} is in the current format", path.display());
+
+    Ok(())
+}
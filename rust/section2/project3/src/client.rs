@@ -1,59 +1,385 @@
-use std::net;
+use std::io;
+use std::thread;
+use std::time::Duration;
 
-use crate::{Request, Response, Result};
+use crate::protocol;
+use crate::protocol::Negotiated;
+use crate::stream::{BufStream, Socket, Stream};
+use crate::tls::tls_error;
+use crate::{
+    Address, Credentials, ErrorCode, KvsError, Request, Response, Result, ServerInfo,
+    TlsClientConfig, WatchEvent,
+};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The builder of `KvsClient`, with its timeouts and retry policy.
+///
+/// Only idempotent requests, i.e. `get`, and connecting are retried, waiting exponentially
+/// longer from the initial backoff up to the maximum backoff between attempts.
+#[derive(Debug, Clone)]
+pub struct KvsClientBuilder {
+    addr: Address,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    tls: Option<TlsClientConfig>,
+    credentials: Option<Credentials>,
+}
+
+impl KvsClientBuilder {
+    /// Creates a builder of a client connecting to the given address,
+    /// which is a tcp socket address or a Unix domain socket, without timeouts or retries.
+    pub fn new(addr: impl Into<Address>) -> KvsClientBuilder {
+        KvsClientBuilder {
+            addr: addr.into(),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_retries: 0,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            tls: None,
+            credentials: None,
+        }
+    }
+
+    /// Sets the timeout of connecting to the server.
+    ///
+    /// `None` waits as long as the operating system does.
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> KvsClientBuilder {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets the timeout of each read from the server, including the handshake.
+    ///
+    /// `None` blocks forever.
+    pub fn read_timeout(mut self, read_timeout: Option<Duration>) -> KvsClientBuilder {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// Sets the timeout of each write to the server, including the handshake.
+    ///
+    /// `None` blocks forever.
+    pub fn write_timeout(mut self, write_timeout: Option<Duration>) -> KvsClientBuilder {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// Sets the number of retries after a timeout, a busy server or a connection error.
+    ///
+    /// 0 disables retries.
+    pub fn max_retries(mut self, max_retries: u32) -> KvsClientBuilder {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sets the backoff before the first retry, which doubles for each following retry.
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> KvsClientBuilder {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets the maximum backoff between retries.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> KvsClientBuilder {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Sets the TLS configuration of connecting to a server which serves TLS.
+    ///
+    /// `None` connects over plain tcp.
+    pub fn tls(mut self, tls: Option<TlsClientConfig>) -> KvsClientBuilder {
+        self.tls = tls;
+        self
+    }
+
+    /// Sets the credentials sent after the handshake, which a server with an `Acl` requires.
+    pub fn credentials(mut self, credentials: Option<Credentials>) -> KvsClientBuilder {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Creates a `KvsClient`, which connects to the server and performs the handshake.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `KvsError::ProtocolMismatch` error
+    /// if the server does not support the protocol of this client.
+    ///
+    /// It returns `KvsError::Timeout` error if the last attempt times out,
+    /// `KvsError::Tls` error if the TLS handshake fails,
+    /// or `KvsError::AuthenticationFailed` error if the server rejects the credentials.
+    pub fn build(self) -> Result<KvsClient> {
+        let (conn, negotiated) = self.retry(|| self.connect())?;
+        Ok(KvsClient {
+            config: self,
+            conn: Some(conn),
+            negotiated,
+        })
+    }
+
+    /// Connects to the server and performs the handshake.
+    fn connect(&self) -> Result<(BufStream, Negotiated)> {
+        let socket = Socket::connect(&self.addr, self.connect_timeout).map_err(timeout_error)?;
+        socket.set_read_timeout(self.read_timeout)?;
+        socket.set_write_timeout(self.write_timeout)?;
+
+        let stream =
+            Stream::connect(socket, &self.addr, self.tls.as_ref()).map_err(timeout_error)?;
+        let mut conn = BufStream::new(stream);
+        let negotiated = protocol::connect(&mut conn, self.credentials.as_ref())
+            .map_err(timeout_error)
+            .map_err(tls_error)?;
+        Ok((conn, negotiated))
+    }
+
+    /// Runs `f` until it succeeds, fails with an error which is not retryable,
+    /// or runs out of retries.
+    fn retry<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match f() {
+                Err(ref e) if retries < self.max_retries && is_connection_error(e) => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
 
 /// The kvs client.
+///
+/// A connection which fails or times out is closed, since a late response may still arrive,
+/// and the next request reconnects to the server.
 pub struct KvsClient {
-    stream: net::TcpStream,
+    config: KvsClientBuilder,
+    /// It is `None` after the connection fails.
+    conn: Option<BufStream>,
+    negotiated: Negotiated,
 }
 
 impl KvsClient {
-    /// Creates a new `KvsClient`.
+    /// Creates a new `KvsClient`, which connects to the server and performs the handshake.
+    ///
+    /// It has neither timeouts nor retries, which can be set by `KvsClientBuilder`.
     ///
     /// # Errors
     ///
-    /// It propagates I/O errors.
-    pub fn new(addr: net::SocketAddr) -> Result<KvsClient> {
-        let stream = net::TcpStream::connect(addr)?;
-        Ok(KvsClient { stream })
+    /// It propagates I/O errors, or returns `KvsError::ProtocolMismatch` error
+    /// if the server does not support the protocol of this client.
+    pub fn new(addr: impl Into<Address>) -> Result<KvsClient> {
+        KvsClientBuilder::new(addr).build()
+    }
+
+    /// Returns the protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u16 {
+        self.negotiated.version
+    }
+
+    /// Returns the capabilities supported by both the client and the server.
+    pub fn capabilities(&self) -> &[String] {
+        &self.negotiated.capabilities
+    }
+
+    /// Checks whether the connection is still usable without blocking.
+    ///
+    /// A connection closed or reset by the server, or with unexpected data to read, is broken.
+    pub(crate) fn is_healthy(&self) -> bool {
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => return false,
+        };
+        if !conn.read_buffer().is_empty() {
+            return false;
+        }
+        let socket = conn.socket();
+        if socket.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let healthy = match socket.probe() {
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        socket.set_nonblocking(false).is_ok() && healthy
     }
 
     /// Sends set command to the server.
     ///
     /// # Errors
     ///
-    /// It propagates bincode serialization and deserialization errors.
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    ///
+    /// It returns `KvsError::Timeout` error if the request times out.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Request::Set { key, value };
-        bincode::serialize_into(&mut self.stream, &command)?;
-
-        let res: Response = bincode::deserialize_from(&mut self.stream)?;
-        Result::from(res)
+        Result::from(self.send(&Request::Set { key, value })?)
     }
 
-    /// Sends get command to the server.
+    /// Sends get command to the server, which is retried by the retry policy.
     ///
     /// # Errors
     ///
-    /// It propagates bincode serialization and deserialization errors.
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    ///
+    /// It returns `KvsError::Timeout` error if the last attempt times out.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let command = Request::Get { key };
-        bincode::serialize_into(&mut self.stream, &command)?;
-
-        let res: Response = bincode::deserialize_from(&mut self.stream)?;
-        Result::from(res)
+        let request = Request::Get { key };
+        let config = self.config.clone();
+        Result::from(config.retry(|| self.send(&request))?)
     }
 
     /// Sends remove command to the server.
     ///
     /// # Errors
     ///
-    /// It propagates bincode serialization and deserialization errors.
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    ///
+    /// It returns `KvsError::Timeout` error if the request times out.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        let command = Request::Remove { key };
-        bincode::serialize_into(&mut self.stream, &command)?;
+        Result::from(self.send(&Request::Remove { key })?)
+    }
+
+    /// Sends info command to the server, which is retried by the retry policy.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    ///
+    /// It returns `KvsError::Timeout` error if the last attempt times out.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        let config = self.config.clone();
+        Result::from(config.retry(|| self.send(&Request::Info))?)
+    }
+
+    /// Sends watch command to the server, which turns the connection into a stream of
+    /// the changes of the key, or of every key starting with it if `prefix` is true.
+    ///
+    /// The read timeout no longer applies, since changes may come at any time.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    ///
+    /// It returns `KvsError::Timeout` error if the request times out.
+    pub fn watch(mut self, key: String, prefix: bool) -> Result<Watcher> {
+        Result::<()>::from(self.send(&Request::Watch { key, prefix })?)?;
+        // The connection is present after a successful request.
+        let conn = self.conn.take().unwrap();
+        conn.socket().set_read_timeout(None)?;
+        Ok(Watcher { conn: Some(conn) })
+    }
+
+    /// Sends a request to the server, and receives the response.
+    ///
+    /// It reconnects to the server if the previous connection failed,
+    /// and closes the connection if this request fails.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, or bincode serialization and deserialization errors.
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let (conn, negotiated) = self.config.connect()?;
+                self.negotiated = negotiated;
+                self.conn.get_or_insert(conn)
+            }
+        };
+
+        let res = protocol::write_frame(&mut *conn, request)
+            .and_then(|()| protocol::read_frame(&mut *conn))
+            .map_err(timeout_error)
+            .map_err(tls_error);
+        match res {
+            Ok(Some(res)) => {
+                // The server closes the connection after a request too large.
+                if let Response::Err {
+                    code: ErrorCode::RequestTooLarge,
+                    ..
+                } = res
+                {
+                    self.conn = None;
+                }
+                Ok(res)
+            }
+            Ok(None) => {
+                self.conn = None;
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+            Err(e) => {
+                self.conn = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The stream of the changes of watched keys, which is returned by `KvsClient::watch`.
+///
+/// It ends when the server closes the connection, e.g. when it is shut down,
+/// and the watch is stopped when it is dropped.
+pub struct Watcher {
+    /// It is `None` after the connection fails.
+    conn: Option<BufStream>,
+}
+
+impl Iterator for Watcher {
+    type Item = Result<WatchEvent>;
+
+    /// Blocks until the next change arrives.
+    fn next(&mut self) -> Option<Self::Item> {
+        let conn = self.conn.as_mut()?;
+        match protocol::read_frame(conn).map_err(tls_error) {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => {
+                self.conn = None;
+                None
+            }
+            Err(e) => {
+                self.conn = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Maps I/O errors of timeouts into `KvsError::Timeout`.
+fn timeout_error(err: impl Into<KvsError>) -> KvsError {
+    match err.into() {
+        KvsError::Io(ref e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            KvsError::Timeout
+        }
+        e => e,
+    }
+}
 
-        let res: Response = bincode::deserialize_from(&mut self.stream)?;
-        Result::from(res)
+/// Whether the error means the connection timed out, or failed to be established,
+/// or was rejected, closed or reset by the server.
+pub(crate) fn is_connection_error(err: &KvsError) -> bool {
+    match err {
+        KvsError::Timeout | KvsError::ServerBusy => true,
+        KvsError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
     }
 }
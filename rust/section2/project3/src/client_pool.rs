@@ -0,0 +1,317 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::client::is_connection_error;
+use crate::{Address, KvsClient, KvsClientBuilder, Result};
+
+const DEFAULT_MIN_CONNECTIONS: usize = 0;
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Options to create a `KvsClientPool`.
+#[derive(Debug, Clone)]
+pub struct KvsClientPoolOptions {
+    min_connections: usize,
+    max_connections: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl KvsClientPoolOptions {
+    /// Creates options with default values.
+    pub fn new() -> KvsClientPoolOptions {
+        KvsClientPoolOptions {
+            min_connections: DEFAULT_MIN_CONNECTIONS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Sets the number of connections opened on creation, which are never closed for idleness.
+    pub fn min_connections(mut self, min_connections: usize) -> KvsClientPoolOptions {
+        self.min_connections = min_connections;
+        self
+    }
+
+    /// Sets the maximum number of open connections.
+    ///
+    /// Once all of them are in use, checking out a connection blocks until one is returned.
+    /// At least one connection is allowed.
+    pub fn max_connections(mut self, max_connections: usize) -> KvsClientPoolOptions {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets the duration after which an idle connection is closed.
+    ///
+    /// Idle connections are closed lazily, when a connection is checked out or returned.
+    /// `None` keeps idle connections open.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> KvsClientPoolOptions {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+}
+
+impl Default for KvsClientPoolOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A thread-safe pool of `KvsClient` connections to a server.
+///
+/// A pool can be cloned into handles for other threads, which share the connections.
+///
+/// Connections are checked for health before reuse, and a request which fails on a reused
+/// connection broken by the server, e.g. a broken pipe, is retried once on a new connection.
+/// A retried `remove` may return `KvsError::KeyNotFound` error, if the first attempt reached
+/// the server.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    /// The builder of every connection of the pool.
+    builder: KvsClientBuilder,
+    options: KvsClientPoolOptions,
+    state: Mutex<PoolState>,
+    /// Notified when a connection is returned or closed.
+    available: Condvar,
+}
+
+struct PoolState {
+    /// The idle connections with the instants they are returned, the most recent last.
+    idle: Vec<(KvsClient, Instant)>,
+    /// The number of idle and checked out connections.
+    open: usize,
+}
+
+/// A connection checked out from a `KvsClientPool`, which is returned on drop.
+pub struct PooledKvsClient {
+    pool: KvsClientPool,
+    client: Option<KvsClient>,
+    /// Whether the connection was idle in the pool, so it may be broken by the server.
+    reused: bool,
+}
+
+impl KvsClientPool {
+    /// Creates a pool with default options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClient::new`.
+    pub fn new(addr: impl Into<Address>) -> Result<KvsClientPool> {
+        Self::with_options(addr, KvsClientPoolOptions::new())
+    }
+
+    /// Creates a pool with the given options, and opens its minimum connections.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClient::new`.
+    pub fn with_options(
+        addr: impl Into<Address>,
+        options: KvsClientPoolOptions,
+    ) -> Result<KvsClientPool> {
+        Self::with_builder(KvsClientBuilder::new(addr), options)
+    }
+
+    /// Creates a pool whose connections are built by the given builder, e.g. with TLS,
+    /// credentials or timeouts, and opens its minimum connections.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClientBuilder::build`.
+    pub fn with_builder(
+        builder: KvsClientBuilder,
+        mut options: KvsClientPoolOptions,
+    ) -> Result<KvsClientPool> {
+        options.max_connections = options.max_connections.max(1);
+        options.min_connections = options.min_connections.min(options.max_connections);
+
+        let now = Instant::now();
+        let idle = (0..options.min_connections)
+            .map(|_| Ok((builder.clone().build()?, now)))
+            .collect::<Result<Vec<_>>>()?;
+        let open = idle.len();
+        Ok(KvsClientPool {
+            inner: Arc::new(PoolInner {
+                builder,
+                options,
+                state: Mutex::new(PoolState { idle, open }),
+                available: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Checks out a connection, which is an idle healthy one or a new one.
+    ///
+    /// It blocks while `max_connections` connections are checked out.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClientBuilder::build`.
+    pub fn get_client(&self) -> Result<PooledKvsClient> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            self.close_expired(&mut state);
+            while let Some((client, _)) = state.idle.pop() {
+                if client.is_healthy() {
+                    return Ok(self.pooled(client, true));
+                }
+                state.open -= 1;
+            }
+
+            if state.open < self.inner.options.max_connections {
+                state.open += 1;
+                drop(state);
+                return match self.inner.builder.clone().build() {
+                    Ok(client) => Ok(self.pooled(client, false)),
+                    Err(e) => {
+                        self.close();
+                        Err(e)
+                    }
+                };
+            }
+            state = self.inner.available.wait(state).unwrap();
+        }
+    }
+
+    /// Sends set command to the server through a pooled connection.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClient::set`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.with_client(|client| client.set(key.clone(), value.clone()))
+    }
+
+    /// Sends get command to the server through a pooled connection.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClient::get`.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.with_client(|client| client.get(key.clone()))
+    }
+
+    /// Sends remove command to the server through a pooled connection.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsClient::remove`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.with_client(|client| client.remove(key.clone()))
+    }
+
+    /// Returns the number of idle connections.
+    pub fn idle_connections(&self) -> usize {
+        self.inner.state.lock().unwrap().idle.len()
+    }
+
+    /// Returns the number of idle and checked out connections.
+    pub fn open_connections(&self) -> usize {
+        self.inner.state.lock().unwrap().open
+    }
+
+    /// Runs a request on a pooled connection, and retries it once on a new connection
+    /// if the reused connection turns out to be broken.
+    fn with_client<R>(&self, f: impl Fn(&mut KvsClient) -> Result<R>) -> Result<R> {
+        let mut client = self.get_client()?;
+        match f(&mut client) {
+            Err(ref e) if client.reused && is_connection_error(e) => {
+                client.discard();
+                let mut client = self.get_fresh_client()?;
+                f(&mut client)
+            }
+            res => res,
+        }
+    }
+
+    /// Checks out a new connection, closing the idle ones which may be broken as well.
+    fn get_fresh_client(&self) -> Result<PooledKvsClient> {
+        let mut state = self.inner.state.lock().unwrap();
+        let closed = state.idle.len();
+        state.idle.clear();
+        state.open -= closed;
+        drop(state);
+        self.inner.available.notify_all();
+        self.get_client()
+    }
+
+    fn pooled(&self, client: KvsClient, reused: bool) -> PooledKvsClient {
+        PooledKvsClient {
+            pool: self.clone(),
+            client: Some(client),
+            reused,
+        }
+    }
+
+    /// Closes the idle connections exceeding the idle timeout, but keeps `min_connections`.
+    fn close_expired(&self, state: &mut MutexGuard<PoolState>) {
+        let idle_timeout = match self.inner.options.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        // The least recently returned connections are the first ones.
+        while state.open > self.inner.options.min_connections
+            && state
+                .idle
+                .first()
+                .is_some_and(|(_, since)| since.elapsed() >= idle_timeout)
+        {
+            state.idle.remove(0);
+            state.open -= 1;
+        }
+    }
+
+    /// Returns a connection into the idle ones.
+    fn put_back(&self, client: KvsClient) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.idle.push((client, Instant::now()));
+        self.close_expired(&mut state);
+        drop(state);
+        self.inner.available.notify_one();
+    }
+
+    /// Accounts a checked out connection which is closed.
+    fn close(&self) {
+        self.inner.state.lock().unwrap().open -= 1;
+        self.inner.available.notify_one();
+    }
+}
+
+impl PooledKvsClient {
+    /// Closes the connection instead of returning it into the pool.
+    pub fn discard(&mut self) {
+        if self.client.take().is_some() {
+            self.pool.close();
+        }
+    }
+}
+
+impl Deref for PooledKvsClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("connection is discarded")
+    }
+}
+
+impl DerefMut for PooledKvsClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("connection is discarded")
+    }
+}
+
+impl Drop for PooledKvsClient {
+    fn drop(&mut self) {
+        // A connection broken while it is checked out is never reused.
+        match self.client.take() {
+            Some(client) if client.is_healthy() => self.pool.put_back(client),
+            Some(_) => self.pool.close(),
+            None => {}
+        }
+    }
+}
@@ -1,8 +1,6 @@
-use std::fmt::Display;
-
 use serde::{Deserialize, Serialize};
 
-use crate::{KvsError, Result};
+use crate::{KvsError, Result, ServerInfo};
 
 /// The command client sends to server.
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,6 +22,94 @@ pub enum Request {
         /// The key which needs to be get.
         key: String,
     },
+    /// Command watch, which turns the connection into a stream of `WatchEvent`s
+    /// after it is responded.
+    Watch {
+        /// The key which needs to be watched.
+        key: String,
+        /// Whether every key starting with the key is watched.
+        prefix: bool,
+    },
+    /// Command info, which is responded with `Response::Info`.
+    Info,
+}
+
+impl Request {
+    /// Returns the name of the command, e.g. "set".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "remove",
+            Request::Get { .. } => "get",
+            Request::Watch { .. } => "watch",
+            Request::Info => "info",
+        }
+    }
+}
+
+/// The change of a key, which server sends to the clients watching it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The key is set to the value.
+    Set {
+        /// The key which is set.
+        key: String,
+        /// The new value of this key.
+        value: String,
+    },
+    /// The key is removed.
+    Remove {
+        /// The key which is removed.
+        key: String,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the key which is changed.
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// The kind of error a server responds with, which the client maps back into `KvsError`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorCode {
+    /// The key to remove does not exist.
+    KeyNotFound,
+    /// The engine of server is opened in read-only mode.
+    ReadOnly,
+    /// The request can not be decoded.
+    InvalidRequest,
+    /// The request exceeds the maximum request size of the server.
+    RequestTooLarge,
+    /// An I/O error occurs in the server.
+    Io,
+    /// The stored data can not be decoded or decrypted.
+    Corrupted,
+    /// Any other error of the server.
+    Internal,
+    /// The user is not permitted to send the request.
+    PermissionDenied,
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(err: &KvsError) -> Self {
+        match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            KvsError::Bincode(_) => ErrorCode::InvalidRequest,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Cbor(_)
+            | KvsError::Json(_)
+            | KvsError::Utf8(_)
+            | KvsError::UnexpectedCommandType
+            | KvsError::Decryption => ErrorCode::Corrupted,
+            _ => ErrorCode::Internal,
+        }
+    }
 }
 
 /// The response server responses to client.
@@ -31,14 +117,29 @@ pub enum Request {
 pub enum Response {
     /// Success response with a message which may be None.
     Ok(Option<String>),
-    /// Error response with a error message.
-    Err(String),
+    /// Error response with an error code and a error message.
+    Err {
+        /// The kind of error.
+        code: ErrorCode,
+        /// The display message of error.
+        message: String,
+    },
+    /// Success response of command info with the statistics of server.
+    Info(ServerInfo),
 }
 
 impl Response {
-    /// Creates response with the given error message.
-    pub fn new_error<T: Display>(err: T) -> Response {
-        Response::Err(err.to_string())
+    /// Creates response with the code and message of the given error.
+    pub fn new_error(err: &KvsError) -> Response {
+        // The reason alone is sent, since the client maps it back into the same error.
+        let message = match err {
+            KvsError::PermissionDenied(reason) => reason.clone(),
+            err => err.to_string(),
+        };
+        Response::Err {
+            code: err.into(),
+            message,
+        }
     }
 
     /// Creates response with the given message which may be None.
@@ -51,7 +152,8 @@ impl From<Response> for Result<()> {
     fn from(res: Response) -> Self {
         match res {
             Response::Ok(_) => Ok(()),
-            Response::Err(e) => Err(KvsError::RemoteError(e)),
+            Response::Err { code, message } => Err(remote_error(code, message)),
+            Response::Info(_) => Err(unexpected_response()),
         }
     }
 }
@@ -60,7 +162,35 @@ impl From<Response> for Result<Option<String>> {
     fn from(res: Response) -> Self {
         match res {
             Response::Ok(msg) => Ok(msg),
-            Response::Err(e) => Err(KvsError::RemoteError(e)),
+            Response::Err { code, message } => Err(remote_error(code, message)),
+            Response::Info(_) => Err(unexpected_response()),
         }
     }
 }
+
+impl From<Response> for Result<ServerInfo> {
+    fn from(res: Response) -> Self {
+        match res {
+            Response::Info(info) => Ok(info),
+            Response::Err { code, message } => Err(remote_error(code, message)),
+            Response::Ok(_) => Err(unexpected_response()),
+        }
+    }
+}
+
+/// The error of a response which does not answer the request, e.g. from a server in another
+/// version.
+fn unexpected_response() -> KvsError {
+    KvsError::ProtocolMismatch("unexpected response".to_owned())
+}
+
+/// Maps an error code back into the specific `KvsError` if there is one,
+/// or else `KvsError::RemoteError`.
+fn remote_error(code: ErrorCode, message: String) -> KvsError {
+    match code {
+        ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+        ErrorCode::ReadOnly => KvsError::ReadOnly,
+        ErrorCode::PermissionDenied => KvsError::PermissionDenied(message),
+        code => KvsError::RemoteError(code, message),
+    }
+}
@@ -1,3 +1,7 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
 use crate::Result;
 
 /// The storage interface called by KvsServer
@@ -18,10 +22,48 @@ pub trait KvsEngine {
     ///
     /// Returns an error if the key does not exit or value is not read successfully.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Gets all the keys in ascending order.
+    ///
+    /// Returns an error if the keys are not read successfully.
+    fn keys(&mut self) -> Result<Vec<String>>;
+
+    /// Flushes the written data and syncs it to disk.
+    ///
+    /// Returns an error if the data is not synced successfully.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Gets the statistics of the stored data.
+    ///
+    /// Returns an error if the data directory is not read successfully.
+    fn engine_stats(&mut self) -> Result<EngineStats>;
+}
+
+/// Statistics of the data stored by a `KvsEngine`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EngineStats {
+    /// The name of the engine, e.g. "kvs" or "sled".
+    pub name: String,
+    /// The number of live keys.
+    pub keys: u64,
+    /// The total size in bytes of the files in the data directory.
+    pub disk_size: u64,
+    /// The bytes of overwritten or removed records which compaction reclaims,
+    /// or `None` if the engine does not track them.
+    pub uncompacted_bytes: Option<u64>,
+    /// The number of log files, or `None` if the engine does not store log files.
+    pub log_files: Option<u64>,
+    /// The number of compactions since the engine is opened,
+    /// or `None` if the engine does not track them.
+    pub compactions: Option<u64>,
+    /// The total duration of the compactions since the engine is opened,
+    /// or `None` if the engine does not track them.
+    pub compaction_duration: Option<Duration>,
 }
 
-pub use self::kvs::KvStore;
+pub use self::kvs::{Cipher, Compression, Encryption, KvStore, KvStoreOptions, KvStoreStats};
 pub use self::sled::SledKvsEngine;
 
 mod kvs;
+mod manifest;
 mod sled;
@@ -1,31 +1,185 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 use serde::{Deserialize, Serialize};
-
-use crate::{KvsEngine, KvsError, Result};
 use serde_cbor::ser::IoWrite;
 
+pub use self::compression::Compression;
+pub use self::encryption::{Cipher, Encryption};
+
+use self::blob::{BlobPointer, BlobWriter};
+use self::cache::ValueCache;
+use self::lock::DirLock;
+use self::reader::{LogFiles, ReaderCache};
+use self::writer::KvStoreWriter;
+use super::manifest::Manifest;
+use crate::{EngineStats, KvsEngine, KvsError, Result};
+
+mod blob;
+mod cache;
+mod compression;
+mod encryption;
+mod lock;
+mod reader;
+mod upgrade;
+mod writer;
+
+const ENGINE_NAME: &str = "kvs";
+/// The current on-disk format, whose log files contain CBOR commands.
+const FORMAT_VERSION: u32 = 2;
+/// The legacy on-disk format, whose log files contain JSON commands and no manifest.
+const JSON_FORMAT_VERSION: u32 = 1;
 const DATA_FILE_EX: &str = "log";
-const COMPACTION_THRESHOLD: u64 = 1024 * 256;
+const COMPACTION_FILE_EX: &str = "compaction";
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 256;
+const DEFAULT_MAX_OPEN_FILES: usize = 64;
+const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024;
+const DEFAULT_CACHE_CAPACITY: usize = 16 * 1024 * 1024;
+const DEFAULT_BLOB_THRESHOLD: usize = 32 * 1024;
+
+/// Options to open a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    max_open_files: usize,
+    max_file_size: u64,
+    compaction_threshold: Option<u64>,
+    cache_capacity: usize,
+    compression: Compression,
+    encryption: Option<Encryption>,
+    blob_threshold: Option<usize>,
+    read_only: bool,
+}
+
+impl KvStoreOptions {
+    /// Creates options with default values.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions {
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            compaction_threshold: Some(DEFAULT_COMPACTION_THRESHOLD),
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            compression: Compression::None,
+            encryption: None,
+            blob_threshold: Some(DEFAULT_BLOB_THRESHOLD),
+            read_only: false,
+        }
+    }
+
+    /// Sets the maximum number of log files kept open for reading.
+    ///
+    /// Readers beyond the limit are closed in LRU order and reopened on demand.
+    /// At least one file is kept open.
+    pub fn max_open_files(mut self, max_open_files: usize) -> KvStoreOptions {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Sets the size in bytes at which the active log file is rotated.
+    ///
+    /// Once the active log file exceeds it, following commands are written into a new log file.
+    pub fn max_file_size(mut self, max_file_size: u64) -> KvStoreOptions {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Sets the number of stale bytes which triggers a compaction.
+    ///
+    /// `None` disables compaction.
+    pub fn compaction_threshold(mut self, compaction_threshold: Option<u64>) -> KvStoreOptions {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Sets the total size in bytes of keys and values kept in the value cache.
+    ///
+    /// 0 disables the cache.
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> KvStoreOptions {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Sets the compression algorithm of values written into the log.
+    ///
+    /// Values which are not smaller after compression are written as they are.
+    /// Compaction rewrites values compressed by another algorithm.
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the encryption of log records.
+    ///
+    /// Each record is encrypted and authenticated separately, including the compaction output.
+    /// Existing log files must be opened with the same key, and compaction rewrites records
+    /// encrypted by another cipher or not encrypted at all.
+    /// `None` disables encryption, and then encrypted log files can not be opened.
+    pub fn encryption(mut self, encryption: Option<Encryption>) -> KvStoreOptions {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Sets the size in bytes from which values, after compression, are stored in blob files.
+    ///
+    /// Log compaction only copies the references of such values instead of the values.
+    /// A blob file is rewritten once half of it is stale, unless compaction is disabled.
+    /// Blob files are rotated by `max_file_size` as well.
+    /// `None` stores all values in log files.
+    pub fn blob_threshold(mut self, blob_threshold: Option<usize>) -> KvStoreOptions {
+        self.blob_threshold = blob_threshold;
+        self
+    }
+
+    /// Sets whether the store is opened in read-only mode.
+    ///
+    /// A read-only store never modifies its data directory: it neither creates a writer
+    /// nor compacts, and `set` and `remove` return `KvsError::ReadOnly` error.
+    /// A missing `LOCK` file is not created, so it can be opened on a read-only mount.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of a `KvStore`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreStats {
+    /// The number of `get`s served by the value cache.
+    pub cache_hits: u64,
+    /// The number of `get`s of existing keys which missed the value cache.
+    pub cache_misses: u64,
+}
 
 /// Default implementation by hand for `KvsEngine`.
 ///
 /// The `KvStore` stores string key/value pairs.
 ///
 /// It persists pairs into files, containing binary `Command` object one by one.
+///
+/// A `KvStore` can be cloned into handles for other threads, which share the index, the writer
+/// and the memory mappings of immutable log files, but own their readers.
+///
+/// The data directory is locked until all the handles are dropped.
+#[derive(Clone)]
 pub struct KvStore {
-    path: PathBuf,
-    writer: io::BufWriter<fs::File>,
-    readers: HashMap<u64, io::BufReader<fs::File>>,
-    index: BTreeMap<String, LogPointer>,
-    cur_file_id: u64,
-    uncompacted: u64,
+    index: Arc<RwLock<BTreeMap<String, LogPointer>>>,
+    readers: ReaderCache,
+    cache: Arc<ValueCache>,
+    encryption: Option<Encryption>,
+    /// The writer is `None` in read-only mode.
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    _lock: Arc<DirLock>,
 }
 
 impl KvsEngine for KvStore {
@@ -36,38 +190,13 @@ impl KvsEngine for KvStore {
     /// # Errors
     ///
     /// It propagates I/O or serialization errors.
+    ///
+    /// It returns `KvsError::ReadOnly` error in read-only mode.
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let offset = self.writer.seek(SeekFrom::End(0))?;
-
-        // Use cloned key, because key is stored in command and index.
-        let cmd = Command::Set {
-            key: key.clone(),
-            value,
-        };
-        cmd.serialize(
-            &mut serde_cbor::Serializer::new(&mut IoWrite::new(&mut self.writer)).packed_format(),
-        )?;
-        self.writer.flush()?;
-
-        let cur_offset = self.writer.seek(SeekFrom::End(0))?;
-        self.index.insert(
-            key,
-            LogPointer {
-                offset,
-                len: cur_offset - offset,
-                file_id: self.cur_file_id,
-            },
-        );
-        if !self.readers.contains_key(&self.cur_file_id) {
-            self.insert_reader(self.cur_file_id)?;
+        match &self.writer {
+            Some(writer) => writer.lock().unwrap().set(key, value),
+            None => Err(KvsError::ReadOnly),
         }
-
-        self.uncompacted += cur_offset - offset;
-        if self.uncompacted >= COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-
-        Ok(())
     }
 
     /// Gets the value of a `key`.
@@ -77,17 +206,25 @@ impl KvsEngine for KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors.
+    ///
+    /// It returns `KvsError::Decryption` if the command can not be decrypted.
     fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
+        // Hold the index until the value is read, so that compaction never removes the file.
+        let index = self.index.read().unwrap();
+        match index.get(&key) {
             Some(pointer) => {
-                let reader = self.readers.get_mut(&pointer.file_id).unwrap();
-                reader.seek(SeekFrom::Start(pointer.offset))?;
-                let cmd_reader = reader.take(pointer.len);
-                if let Command::Set { value, .. } = serde_cbor::from_reader(cmd_reader)? {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
+                if let Some(value) = self.cache.get(&key, pointer) {
+                    return Ok(Some(value));
                 }
+
+                // Large values are read from blob files without reading the log.
+                let cmd: Command = match &pointer.blob {
+                    Some(blob) => serde_cbor::from_reader(self.readers.blob_reader(blob)?)?,
+                    None => serde_cbor::from_reader(self.readers.command_reader(pointer)?)?,
+                };
+                let value = cmd.decrypt(self.encryption.as_ref())?.into_value()?;
+                self.cache.insert(key, *pointer, value.clone());
+                Ok(Some(value))
             }
             None => Ok(None),
         }
@@ -99,38 +236,69 @@ impl KvsEngine for KvStore {
     ///
     /// It propagates I/O or serialization errors.
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key does not exist.
+    /// It returns `KvsError::KeyNotFound` if the given key does not exist,
+    /// or `KvsError::ReadOnly` error in read-only mode.
     fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let offset = self.writer.seek(SeekFrom::End(0))?;
-
-            let cmd = Command::Remove { key: key.clone() };
+        match &self.writer {
+            Some(writer) => writer.lock().unwrap().remove(key),
+            None => Err(KvsError::ReadOnly),
+        }
+    }
 
-            cmd.serialize(
-                &mut serde_cbor::Serializer::new(&mut IoWrite::new(&mut self.writer))
-                    .packed_format(),
-            )?;
-            self.writer.flush()?;
+    /// Gets all the keys in ascending order from the index.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.index.read().unwrap().keys().cloned().collect())
+    }
 
-            self.index.remove(&key);
-            if !self.readers.contains_key(&self.cur_file_id) {
-                self.insert_reader(self.cur_file_id)?;
-            }
+    /// Syncs the active log file and blob file to disk, which does nothing in read-only mode.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn flush(&mut self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => writer.lock().unwrap().sync(),
+            None => Ok(()),
+        }
+    }
 
-            self.uncompacted += self.writer.seek(SeekFrom::End(0))? - offset;
-            if self.uncompacted >= COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Gets the statistics of the data directory.
+    ///
+    /// The uncompacted bytes and compactions are not tracked in read-only mode.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn engine_stats(&mut self) -> Result<EngineStats> {
+        let path = self.readers.path();
+        let mut disk_size = 0;
+        for entry in fs::read_dir(path)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                disk_size += metadata.len();
             }
-
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
+        let (uncompacted_bytes, compactions) = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                (Some(writer.uncompacted()), Some(writer.compactions()))
+            }
+            None => (None, None),
+        };
+        Ok(EngineStats {
+            name: ENGINE_NAME.to_owned(),
+            keys: self.index.read().unwrap().len() as u64,
+            disk_size,
+            uncompacted_bytes,
+            log_files: Some(Self::sorted_file_ids(path)?.len() as u64),
+            compactions: compactions.map(|(count, _)| count),
+            compaction_duration: compactions.map(|(_, duration)| duration),
+        })
     }
 }
 
 impl KvStore {
-    /// Opens a `KvStore` with the given path.
+    /// Opens a `KvStore` with the given path and default options.
     ///
     /// It will create all the path, if the path does not exist.
     ///
@@ -138,125 +306,235 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::new())
+    }
+
+    /// Opens a `KvStore` in read-only mode with the given path and default options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with_options(path, KvStoreOptions::new().read_only(true))
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// It will create all the path, if the path does not exist and it is not in read-only mode.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors.
+    ///
+    /// It returns `KvsError::Decryption` if the log files are encrypted by another key,
+    /// or encrypted while `options` has no encryption.
+    ///
+    /// It returns `KvsError::WrongEngineType` or `KvsError::IncompatibleFormat` error,
+    /// if the data directory is written by another engine or in another format.
+    ///
+    /// It returns `KvsError::Locked` error, if the data directory is opened by another process
+    /// for writing, or it is opened by another process in read-only mode and `options` is not.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let lock = if options.read_only {
+            DirLock::lock_shared(&path)?
+        } else {
+            fs::create_dir_all(&path)?;
+            DirLock::lock(&path)?
+        };
+        Self::check_format(&path, &options)?;
+        if !options.read_only {
+            Self::remove_compaction_files(&path)?;
+        }
 
-        let mut readers = HashMap::new();
         let mut index = BTreeMap::new();
-        let mut uncompacted = 0;
+        let mut stale = BTreeMap::new();
         let file_ids = Self::sorted_file_ids(&path)?;
         for &file_id in &file_ids {
-            let mut reader = io::BufReader::new(fs::File::open(Self::log_path(&path, file_id))?);
-            uncompacted += Self::load_index(&mut reader, &mut index, file_id)?;
-            readers.insert(file_id, reader);
+            let mut reader = BufReader::new(File::open(Self::log_path(&path, file_id))?);
+            Self::load_index(
+                &mut reader,
+                &mut index,
+                &mut stale,
+                file_id,
+                options.encryption.as_ref(),
+            )?;
         }
 
         let cur_file_id = *file_ids.last().unwrap_or(&0);
-        let writer = Self::new_log_writer(&path, cur_file_id)?;
+        // All log files are immutable in read-only mode.
+        let active_file_id = if options.read_only {
+            cur_file_id + 1
+        } else {
+            cur_file_id
+        };
+        let files = Arc::new(LogFiles::new(path.clone(), active_file_id));
+        let index = Arc::new(RwLock::new(index));
+        let readers = ReaderCache::new(Arc::clone(&files), options.max_open_files);
+        let cache = Arc::new(ValueCache::new(options.cache_capacity));
+        let encryption = options.encryption.clone();
+
+        let writer = if options.read_only {
+            None
+        } else {
+            Self::manifest(&options).store(&path)?;
+            let blobs = BlobWriter::open(
+                Arc::clone(&files),
+                index
+                    .read()
+                    .unwrap()
+                    .values()
+                    .filter_map(|pointer| pointer.blob.as_ref()),
+            )?;
+            let writer = KvStoreWriter::new(
+                files,
+                blobs,
+                Arc::clone(&index),
+                Arc::clone(&cache),
+                cur_file_id,
+                stale,
+                options,
+            )?;
+            Some(Arc::new(Mutex::new(writer)))
+        };
 
         Ok(KvStore {
-            path,
-            writer,
-            readers,
             index,
-            cur_file_id,
-            uncompacted,
+            readers,
+            cache,
+            encryption,
+            writer,
+            _lock: Arc::new(lock),
         })
     }
 
-    /// Compacts all log files into one file.
+    /// Returns the statistics of this store.
+    pub fn stats(&self) -> KvStoreStats {
+        let (cache_hits, cache_misses) = self.cache.hits_and_misses();
+        KvStoreStats {
+            cache_hits,
+            cache_misses,
+        }
+    }
+
+    /// Checks the manifest of the data directory.
+    ///
+    /// A data directory without manifest is checked by its log files,
+    /// and the manifest is created after it is opened.
     ///
-    /// Writes compacted set commands into the log file with id `self.cur_file_id + 1` using index.
-    /// Changes current file with id from `self.cur_file_id` to `self.cur_file_id + 2`.
+    /// Only encryption must match the manifest. Every record tells its own compression,
+    /// and every `SetBlob` command points to its blob, so a store written with another
+    /// compression or blob threshold is read as it is, and its old records are encoded again
+    /// by compaction and blob collection.
     ///
     /// # Errors
     ///
-    /// It propagates I/O errors.
-    fn compact(&mut self) -> Result<()> {
-        let compaction_file_id = self.cur_file_id + 1;
-        self.cur_file_id += 2;
-
-        let mut compaction_writer = Self::new_log_writer(&self.path, compaction_file_id)?;
-        self.writer = Self::new_log_writer(&self.path, self.cur_file_id)?;
-        self.insert_reader(compaction_file_id)?;
-
-        let mut pre_offset = compaction_writer.seek(SeekFrom::Current(0))?;
-
-        for pointer in self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&pointer.file_id)
-                .expect("readers does not contain this file_id");
-            reader.seek(SeekFrom::Start(pointer.offset))?;
-            let mut cmd_reader = reader.take(pointer.len);
-
-            io::copy(&mut cmd_reader, &mut compaction_writer)?;
-
-            let cur_offset = compaction_writer.seek(SeekFrom::Current(0))?;
-            pointer.file_id = compaction_file_id;
-            pointer.offset = pre_offset;
-            pointer.len = cur_offset - pre_offset;
-            pre_offset = cur_offset;
-        }
-
-        let stale_file_ids: Vec<u64> = self
-            .readers
-            .keys()
-            .filter(|&&file_id| file_id < compaction_file_id)
-            .cloned()
-            .collect();
-        for file_id in stale_file_ids {
-            fs::remove_file(Self::log_path(&self.path, file_id))?;
-            self.readers.remove(&file_id);
+    /// It propagates I/O errors, or returns `KvsError::WrongEngineType` or
+    /// `KvsError::IncompatibleFormat` error.
+    fn check_format(path: &Path, options: &KvStoreOptions) -> Result<()> {
+        match Manifest::load(path)? {
+            Some(manifest) => {
+                manifest.check(ENGINE_NAME, FORMAT_VERSION)?;
+                match manifest.get_option("encryption") {
+                    Some(cipher) if cipher != "none" && options.encryption.is_none() => {
+                        Err(KvsError::IncompatibleFormat(format!(
+                            "the store is encrypted by {}, but no encryption key is given",
+                            cipher
+                        )))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            None => match Self::legacy_format_version(path)? {
+                FORMAT_VERSION => Ok(()),
+                format_version => Err(KvsError::IncompatibleFormat(format!(
+                    "log files are in format version {}, upgrade them by `kvs-upgrade` first",
+                    format_version
+                ))),
+            },
         }
-
-        self.uncompacted = 0;
-
-        Ok(())
     }
 
-    /// Inserts file reader ("self.path/<file_id>.log")) to readers.
-    ///
-    /// # Errors
-    ///
-    /// It propagates I/O errors.
-    fn insert_reader(&mut self, file_id: u64) -> Result<()> {
-        self.readers.insert(
-            file_id,
-            io::BufReader::new(fs::File::open(Self::log_path(&self.path, file_id))?),
-        );
-        Ok(())
+    /// Creates the manifest recording the given options.
+    fn manifest(options: &KvStoreOptions) -> Manifest {
+        let encryption = match &options.encryption {
+            Some(encryption) => encryption.cipher().to_string(),
+            None => "none".to_owned(),
+        };
+        let blob_threshold = match options.blob_threshold {
+            Some(blob_threshold) => blob_threshold.to_string(),
+            None => "none".to_owned(),
+        };
+        Manifest::new(ENGINE_NAME, FORMAT_VERSION)
+            .option("compression", options.compression)
+            .option("encryption", encryption)
+            .option("blob_threshold", blob_threshold)
+            .option("max_file_size", options.max_file_size)
     }
 
-    /// Loads index from disk into `HashMap` and return the number of read bytes.
+    /// Loads index from disk into `BTreeMap` and counts stale bytes of each file.
     ///
     /// # Errors
     ///
-    /// It propagates I/O and deserialization errors.
+    /// It propagates I/O and deserialization errors, or returns `KvsError::Decryption` error.
     fn load_index(
         reader: &mut BufReader<File>,
         index: &mut BTreeMap<String, LogPointer>,
+        stale: &mut BTreeMap<u64, u64>,
         file_id: u64,
-    ) -> Result<u64> {
+        encryption: Option<&Encryption>,
+    ) -> Result<()> {
         let mut pre_offset = reader.seek(SeekFrom::Start(0))?;
         let mut iterator = serde_cbor::Deserializer::from_reader(reader).into_iter::<Command>();
         while let Some(cmd) = iterator.next() {
             let offset = iterator.byte_offset() as u64;
-            match cmd? {
-                Command::Set { key, .. } => index.insert(
+            let old_pointer = match cmd?.decrypt(encryption)? {
+                Command::Set { key, .. } | Command::SetCompressed { key, .. } => index.insert(
                     key,
                     LogPointer {
                         offset: pre_offset,
                         len: offset - pre_offset,
                         file_id,
+                        blob: None,
                     },
                 ),
-                Command::Remove { key } => index.remove(&key),
+                Command::SetBlob { key, blob } => index.insert(
+                    key,
+                    LogPointer {
+                        offset: pre_offset,
+                        len: offset - pre_offset,
+                        file_id,
+                        blob: Some(blob),
+                    },
+                ),
+                Command::Remove { key } => {
+                    *stale.entry(file_id).or_insert(0) += offset - pre_offset;
+                    index.remove(&key)
+                }
+                Command::Encrypted { .. } => return Err(KvsError::UnexpectedCommandType),
             };
+            if let Some(old_pointer) = old_pointer {
+                *stale.entry(old_pointer.file_id).or_insert(0) += old_pointer.len;
+            }
             pre_offset = offset;
         }
 
-        Ok(pre_offset)
+        Ok(())
+    }
+
+    /// Removes temporary files left by an interrupted compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn remove_compaction_files(path: &Path) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension() == Some(OsStr::new(COMPACTION_FILE_EX)) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
     }
 
     /// Gets sorted file id list in the given path.
@@ -266,7 +544,7 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O errors.
-    fn sorted_file_ids(path: &PathBuf) -> Result<Vec<u64>> {
+    fn sorted_file_ids(path: &Path) -> Result<Vec<u64>> {
         let mut file_ids: Vec<u64> = fs::read_dir(path)?
             .flat_map(|res| -> Result<_> { Ok(res?.path()) })
             .filter(|path| path.is_file() && path.extension() == Some(OsStr::new(DATA_FILE_EX)))
@@ -287,31 +565,214 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O and deserialization errors.
-    fn new_log_writer(path: &PathBuf, file_id: u64) -> Result<io::BufWriter<File>> {
+    fn new_log_writer(path: &Path, file_id: u64) -> Result<io::BufWriter<File>> {
         let file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
             .open(Self::log_path(path, file_id))?;
         Ok(io::BufWriter::new(file))
     }
 
-    fn log_path(path: &PathBuf, file_id: u64) -> PathBuf {
+    /// Syncs the entries of the data directory to disk, so that created, renamed
+    /// and removed files survive a power loss.
+    ///
+    /// Directories can not be synced on Windows, where it does nothing.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn sync_dir(path: &Path) -> Result<()> {
+        #[cfg(unix)]
+        File::open(path)?.sync_all()?;
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+
+    fn log_path(path: &Path, file_id: u64) -> PathBuf {
         path.join(format!("{}.{}", file_id, DATA_FILE_EX))
     }
+
+    fn compaction_path(path: &Path, file_id: u64) -> PathBuf {
+        path.join(format!("{}.{}", file_id, COMPACTION_FILE_EX))
+    }
 }
 
 /// The pointer of a command in the persistence file.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct LogPointer {
     offset: u64,
     len: u64,
     file_id: u64,
+    /// The pointer of the value in a blob file, if the command is a `SetBlob` command.
+    blob: Option<BlobPointer>,
 }
 
 /// The command which needs to be persisted in files.
 #[derive(Serialize, Deserialize)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Set command whose value is compressed by `compression`.
+    SetCompressed {
+        key: String,
+        compression: Compression,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    /// Command encrypted by `cipher`, whose plaintext is another serialized command.
+    Encrypted {
+        cipher: Cipher,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+    },
+    /// Set command whose value is stored in a blob file, as a serialized set command.
+    SetBlob {
+        key: String,
+        blob: BlobPointer,
+    },
+}
+
+impl Command {
+    /// Creates a set command whose value is compressed by `compression`.
+    ///
+    /// The value is kept as it is, if it is not smaller after compression.
+    ///
+    /// # Errors
+    ///
+    /// It propagates compression errors.
+    fn set(key: String, value: String, compression: Compression) -> Result<Command> {
+        if compression != Compression::None {
+            let compressed = compression.compress(value.as_bytes())?;
+            if compressed.len() < value.len() {
+                return Ok(Command::SetCompressed {
+                    key,
+                    compression,
+                    value: compressed,
+                });
+            }
+        }
+        Ok(Command::Set { key, value })
+    }
+
+    /// Returns whether the value of a set command should be compressed by `compression`
+    /// instead of the current one.
+    fn needs_recompression(&self, compression: Compression) -> bool {
+        match self {
+            Command::Set { .. } => compression != Compression::None,
+            Command::SetCompressed {
+                compression: cur_compression,
+                ..
+            } => *cur_compression != compression,
+            Command::Remove { .. } | Command::Encrypted { .. } | Command::SetBlob { .. } => false,
+        }
+    }
+
+    /// Returns the length in bytes of the value of a set command, which may be compressed.
+    fn value_len(&self) -> usize {
+        match self {
+            Command::Set { value, .. } => value.len(),
+            Command::SetCompressed { value, .. } => value.len(),
+            _ => 0,
+        }
+    }
+
+    /// Returns the cipher of an encrypted command.
+    fn cipher(&self) -> Option<Cipher> {
+        match self {
+            Command::Encrypted { cipher, .. } => Some(*cipher),
+            _ => None,
+        }
+    }
+
+    /// Encrypts the command by `encryption`, or returns it as it is if `encryption` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates serialization errors, or returns `KvsError::Encryption` error.
+    fn encrypt(self, encryption: Option<&Encryption>) -> Result<Command> {
+        match encryption {
+            Some(encryption) => {
+                let mut plaintext = Vec::new();
+                self.write_to(&mut plaintext)?;
+                let (nonce, ciphertext) = encryption.encrypt(&plaintext)?;
+                Ok(Command::Encrypted {
+                    cipher: encryption.cipher(),
+                    nonce,
+                    ciphertext,
+                })
+            }
+            None => Ok(self),
+        }
+    }
+
+    /// Decrypts an encrypted command by `encryption`, or returns other commands as they are.
+    ///
+    /// # Errors
+    ///
+    /// It propagates deserialization errors, or returns `KvsError::Decryption` error
+    /// if the key is wrong or `encryption` is `None`.
+    fn decrypt(self, encryption: Option<&Encryption>) -> Result<Command> {
+        match self {
+            Command::Encrypted {
+                cipher,
+                nonce,
+                ciphertext,
+            } => {
+                let encryption = encryption.ok_or(KvsError::Decryption)?;
+                let plaintext = encryption.decrypt(cipher, &nonce, &ciphertext)?;
+                Ok(serde_cbor::from_slice(&plaintext)?)
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Returns the value of a set command, which is decompressed if needed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates decompression errors, or returns `KvsError::UnexpectedCommandType` error
+    /// for a remove command.
+    fn into_value(self) -> Result<String> {
+        Ok(self.into_key_value()?.1)
+    }
+
+    /// Returns the key and the value of a set command, which is decompressed if needed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates decompression errors, or returns `KvsError::UnexpectedCommandType` error
+    /// for a remove command.
+    fn into_key_value(self) -> Result<(String, String)> {
+        match self {
+            Command::Set { key, value } => Ok((key, value)),
+            Command::SetCompressed {
+                key,
+                compression,
+                value,
+            } => Ok((key, String::from_utf8(compression.decompress(&value)?)?)),
+            Command::Remove { .. } | Command::Encrypted { .. } | Command::SetBlob { .. } => {
+                Err(KvsError::UnexpectedCommandType)
+            }
+        }
+    }
+
+    /// Serializes the command into `writer`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates serialization errors.
+    fn write_to(&self, mut writer: impl Write) -> Result<()> {
+        self.serialize(
+            &mut serde_cbor::Serializer::new(&mut IoWrite::new(&mut writer)).packed_format(),
+        )?;
+        Ok(())
+    }
 }
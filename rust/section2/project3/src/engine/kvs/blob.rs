@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::reader::LogFiles;
+use crate::Result;
+
+pub(super) const BLOB_FILE_EX: &str = "blob";
+
+/// The pointer of a value record in a blob file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(super) struct BlobPointer {
+    pub(super) file_id: u64,
+    pub(super) offset: u64,
+    pub(super) len: u64,
+}
+
+/// Gets the path of the blob file ("path/<file_id>.blob").
+pub(super) fn blob_path(path: &Path, file_id: u64) -> PathBuf {
+    path.join(format!("{}.{}", file_id, BLOB_FILE_EX))
+}
+
+/// The writer of blob files, which holds values too large to be copied by log compaction.
+///
+/// It appends records into the active blob file, and tracks the live bytes of each blob file,
+/// so that blob files are collected independently of log files.
+pub(super) struct BlobWriter {
+    files: Arc<LogFiles>,
+    writer: BufWriter<File>,
+    cur_file_id: u64,
+    cur_offset: u64,
+    /// The total bytes of each blob file.
+    sizes: BTreeMap<u64, u64>,
+    /// The bytes of each blob file referred by the index.
+    live: BTreeMap<u64, u64>,
+}
+
+impl BlobWriter {
+    /// Opens the blob files of the log files, given the blob pointers of the index.
+    ///
+    /// Blob files which are not referred by the index are removed,
+    /// and the newest blob file is the active one, which readers do not map.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn open<'a>(
+        files: Arc<LogFiles>,
+        blobs: impl Iterator<Item = &'a BlobPointer>,
+    ) -> Result<BlobWriter> {
+        let path = files.path().clone();
+        let mut live = BTreeMap::new();
+        for blob in blobs {
+            *live.entry(blob.file_id).or_insert(0) += blob.len;
+        }
+
+        let file_ids = Self::sorted_file_ids(&path)?;
+        let cur_file_id = *file_ids.last().unwrap_or(&0);
+        let mut sizes = BTreeMap::new();
+        for file_id in file_ids {
+            if file_id != cur_file_id && !live.contains_key(&file_id) {
+                fs::remove_file(blob_path(&path, file_id))?;
+            } else {
+                let size = fs::metadata(blob_path(&path, file_id))?.len();
+                sizes.insert(file_id, size);
+            }
+        }
+
+        let (writer, cur_offset) = Self::new_blob_writer(&path, cur_file_id)?;
+        sizes.insert(cur_file_id, cur_offset);
+        files.set_active_blob_file_id(cur_file_id);
+        Ok(BlobWriter {
+            files,
+            writer,
+            cur_file_id,
+            cur_offset,
+            sizes,
+            live,
+        })
+    }
+
+    /// Appends the record into the active blob file and returns the pointer of it.
+    ///
+    /// Rotates the active blob file, if its size exceeds `max_file_size` after appending.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn append(&mut self, record: &[u8], max_file_size: u64) -> Result<BlobPointer> {
+        self.writer.write_all(record)?;
+        self.writer.flush()?;
+
+        let blob = BlobPointer {
+            file_id: self.cur_file_id,
+            offset: self.cur_offset,
+            len: record.len() as u64,
+        };
+        self.cur_offset += blob.len;
+        *self.sizes.entry(blob.file_id).or_insert(0) += blob.len;
+        *self.live.entry(blob.file_id).or_insert(0) += blob.len;
+
+        if self.cur_offset >= max_file_size {
+            // The immutable file is synced once, so that `sync` only syncs the active one.
+            self.writer.get_ref().sync_all()?;
+            self.cur_file_id += 1;
+            let (writer, cur_offset) = Self::new_blob_writer(self.files.path(), self.cur_file_id)?;
+            self.writer = writer;
+            self.cur_offset = cur_offset;
+            self.sizes.insert(self.cur_file_id, cur_offset);
+            self.files.set_active_blob_file_id(self.cur_file_id);
+        }
+
+        Ok(blob)
+    }
+
+    /// Syncs the active blob file to disk.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Records the record pointed by `blob` as stale.
+    ///
+    /// Returns the id of its blob file, if the file is immutable and at least half stale.
+    pub(super) fn mark_stale(&mut self, blob: &BlobPointer) -> Option<u64> {
+        let live = self.live.entry(blob.file_id).or_insert(0);
+        *live -= blob.len;
+        let size = self.sizes.get(&blob.file_id).copied().unwrap_or(0);
+        if blob.file_id != self.cur_file_id && *live * 2 <= size {
+            Some(blob.file_id)
+        } else {
+            None
+        }
+    }
+
+    /// Removes a blob file, whose live records have been moved away.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn remove_file(&mut self, file_id: u64) -> Result<()> {
+        self.sizes.remove(&file_id);
+        self.live.remove(&file_id);
+        self.files.remove_blob_file(file_id);
+        fs::remove_file(blob_path(self.files.path(), file_id))?;
+        Ok(())
+    }
+
+    /// Gets sorted blob file id list in the given path.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn sorted_file_ids(path: &Path) -> Result<Vec<u64>> {
+        let mut file_ids: Vec<u64> = fs::read_dir(path)?
+            .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+            .filter(|path| path.is_file() && path.extension() == Some(OsStr::new(BLOB_FILE_EX)))
+            .flat_map(|path| {
+                path.file_stem()
+                    .and_then(OsStr::to_str)
+                    .map(str::parse::<u64>)
+            })
+            .flatten()
+            .collect();
+        file_ids.sort_unstable();
+        Ok(file_ids)
+    }
+
+    /// Creates new blob file writer, and returns it with the size of the file.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn new_blob_writer(path: &Path, file_id: u64) -> Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(blob_path(path, file_id))?;
+        let size = file.metadata()?.len();
+        Ok((BufWriter::new(file), size))
+    }
+}
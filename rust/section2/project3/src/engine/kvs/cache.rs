@@ -0,0 +1,117 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+use super::LogPointer;
+
+const SHARDS: usize = 16;
+
+/// The cached value of a key, with the pointer of the command it is read from.
+struct Entry {
+    pointer: LogPointer,
+    value: String,
+}
+
+/// A shard of the value cache, bounded by the total size of its keys and values.
+struct Shard {
+    entries: LruCache<String, Entry>,
+    size: usize,
+}
+
+/// The LRU cache of values shared by all handles of a `KvStore`.
+///
+/// Keys are spread over shards, each one guarded by its own lock,
+/// so that concurrent readers rarely wait for each other.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// Creates a value cache holding at most `capacity` bytes of keys and values.
+    ///
+    /// The cache is disabled, if `capacity` is 0.
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    entries: LruCache::unbounded(),
+                    size: 0,
+                })
+            })
+            .collect();
+        ValueCache {
+            shards,
+            shard_capacity: capacity / SHARDS,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets the cached value of `key`.
+    ///
+    /// The value is only returned, if it is read from the command `pointer` points to.
+    pub(super) fn get(&self, key: &str, pointer: &LogPointer) -> Option<String> {
+        let value = match self.shard(key).lock().unwrap().entries.get(key) {
+            Some(entry) if entry.pointer == *pointer => Some(entry.value.clone()),
+            _ => None,
+        };
+        match value {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        value
+    }
+
+    /// Caches the value of `key` read from the command `pointer` points to.
+    ///
+    /// The least recently used values are evicted, until the shard fits its capacity.
+    pub(super) fn insert(&self, key: String, pointer: LogPointer, value: String) {
+        let size = key.len() + value.len();
+        if size > self.shard_capacity {
+            return;
+        }
+
+        let mut shard = self.shard(&key).lock().unwrap();
+        if let Some(old_entry) = shard.entries.pop(&key) {
+            shard.size -= key.len() + old_entry.value.len();
+        }
+        while shard.size + size > self.shard_capacity {
+            match shard.entries.pop_lru() {
+                Some((old_key, old_entry)) => {
+                    shard.size -= old_key.len() + old_entry.value.len();
+                }
+                None => break,
+            }
+        }
+        shard.size += size;
+        shard.entries.put(key, Entry { pointer, value });
+    }
+
+    /// Removes the cached value of `key`.
+    pub(super) fn remove(&self, key: &str) {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Some(entry) = shard.entries.pop(key) {
+            shard.size -= key.len() + entry.value.len();
+        }
+    }
+
+    /// Returns the number of cache hits and misses.
+    pub(super) fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+}
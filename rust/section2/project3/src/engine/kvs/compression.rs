@@ -0,0 +1,57 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// The compression algorithm of values written into the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// Values are written as they are.
+    None,
+    /// Values are compressed by LZ4, which is fast.
+    Lz4,
+    /// Values are compressed by zstd, which has a better ratio.
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// The zstd level used by the zstd library by default.
+const ZSTD_DEFAULT_LEVEL: i32 = 0;
+
+impl Compression {
+    /// Compresses the given bytes.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the compression library.
+    pub(super) fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4::block::compress(bytes, None, true)?,
+            Compression::Zstd => zstd::encode_all(bytes, ZSTD_DEFAULT_LEVEL)?,
+        })
+    }
+
+    /// Decompresses the given bytes compressed by `self`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the compression library.
+    pub(super) fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4::block::decompress(bytes, None)?,
+            Compression::Zstd => zstd::decode_all(bytes)?,
+        })
+    }
+}
@@ -0,0 +1,139 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// The length in bytes of encryption keys.
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// The authenticated encryption algorithm of log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter Mode, which is fast with hardware AES support.
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, which is fast without hardware AES support.
+    ChaCha20Poly1305,
+}
+
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(Cipher::Aes256Gcm),
+            "chacha20-poly1305" => Ok(Cipher::ChaCha20Poly1305),
+            _ => Err(format!(
+                "unknown cipher {}, expected aes-256-gcm or chacha20-poly1305",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::Aes256Gcm => write!(f, "aes-256-gcm"),
+            Cipher::ChaCha20Poly1305 => write!(f, "chacha20-poly1305"),
+        }
+    }
+}
+
+/// The cipher and the key to encrypt log records.
+///
+/// Records are always decrypted by the cipher recorded in themselves,
+/// so the cipher can be changed with the same key.
+#[derive(Clone)]
+pub struct Encryption {
+    cipher: Cipher,
+    key: [u8; KEY_LEN],
+}
+
+impl Encryption {
+    /// Creates an encryption with the given cipher and key.
+    pub fn new(cipher: Cipher, key: [u8; KEY_LEN]) -> Encryption {
+        Encryption { cipher, key }
+    }
+
+    /// Creates an encryption with the given cipher and key in hex.
+    ///
+    /// Leading and trailing whitespaces of `hex_key` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidEncryptionKey` if `hex_key` is not `2 * KEY_LEN` hex digits.
+    pub fn from_hex(cipher: Cipher, hex_key: &str) -> Result<Encryption> {
+        let hex_key = hex_key.trim();
+        if hex_key.len() != 2 * KEY_LEN || !hex_key.is_ascii() {
+            return Err(KvsError::InvalidEncryptionKey);
+        }
+
+        let mut key = [0; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex_key[2 * i..2 * i + 2], 16)
+                .map_err(|_| KvsError::InvalidEncryptionKey)?;
+        }
+        Ok(Encryption::new(cipher, key))
+    }
+
+    /// Returns the cipher used to encrypt new records.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Encrypts `plaintext` with a random nonce, and returns the nonce and the ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Encryption` if the cipher fails.
+    pub(super) fn encrypt(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let key = &self.key.into();
+        let ciphertext = match self.cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key).encrypt(&nonce.into(), plaintext),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key).encrypt(&nonce.into(), plaintext)
+            }
+        }
+        .map_err(|_| KvsError::Encryption)?;
+        Ok((nonce.to_vec(), ciphertext))
+    }
+
+    /// Decrypts `ciphertext` which is encrypted by `cipher` with `nonce`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Decryption` if the key is wrong or the ciphertext is corrupted.
+    pub(super) fn decrypt(
+        &self,
+        cipher: Cipher,
+        nonce: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| KvsError::Decryption)?;
+        let key = &self.key.into();
+        match cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key).decrypt(&nonce.into(), ciphertext),
+            Cipher::ChaCha20Poly1305 => {
+                ChaCha20Poly1305::new(key).decrypt(&nonce.into(), ciphertext)
+            }
+        }
+        .map_err(|_| KvsError::Decryption)
+    }
+}
+
+impl fmt::Debug for Encryption {
+    /// Formats the cipher only, so that the key never appears in logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
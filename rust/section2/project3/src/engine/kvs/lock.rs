@@ -0,0 +1,88 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+const LOCK_FILENAME: &str = "LOCK";
+
+/// The advisory lock of a data directory, which is released on drop.
+///
+/// It prevents other processes from opening the same data directory
+/// and interleaving appends to the same log file.
+pub(super) struct DirLock {
+    /// The locked files, i.e. the `LOCK` file and, on Unix, the directory itself.
+    files: Vec<File>,
+}
+
+impl DirLock {
+    /// Locks the data directory in the given path through its `LOCK` file.
+    ///
+    /// On Unix, the directory itself is locked as well, so that a reader of a directory
+    /// without `LOCK` file excludes writers.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` error if the lock is held by another process or handle,
+    /// or propagates other I/O errors.
+    pub(super) fn lock(path: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILENAME))?;
+        // Call the trait method explicitly, since `File` has inherent lock methods in newer std.
+        FileExt::try_lock_exclusive(&file).map_err(Self::lock_error)?;
+        let mut files = vec![file];
+        if cfg!(unix) {
+            let dir = File::open(path)?;
+            FileExt::try_lock_exclusive(&dir).map_err(Self::lock_error)?;
+            files.push(dir);
+        }
+        Ok(DirLock { files })
+    }
+
+    /// Locks the data directory in the given path through its `LOCK` file in shared mode,
+    /// which is only held with other shared locks.
+    ///
+    /// The directory is never modified. An existing `LOCK` file is only opened for reading.
+    /// Without one, the directory itself is locked on Unix, and nothing is locked elsewhere.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` error if the data directory is opened for writing,
+    /// or propagates other I/O errors.
+    pub(super) fn lock_shared(path: &Path) -> Result<DirLock> {
+        let file = match File::open(path.join(LOCK_FILENAME)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !cfg!(unix) {
+                    return Ok(DirLock { files: Vec::new() });
+                }
+                File::open(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+        FileExt::try_lock_shared(&file).map_err(Self::lock_error)?;
+        Ok(DirLock { files: vec![file] })
+    }
+
+    fn lock_error(e: io::Error) -> KvsError {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            KvsError::Locked
+        } else {
+            KvsError::Io(e)
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // The lock is released on close anyway, so the error is ignored.
+        for file in &self.files {
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
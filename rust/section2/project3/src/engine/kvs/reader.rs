@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use memmap2::Mmap;
+
+use super::blob::{self, BlobPointer};
+use super::{KvStore, LogPointer};
+use crate::Result;
+
+/// The kind of data files, which are read through the same cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FileKind {
+    Log,
+    Blob,
+}
+
+/// The state of log files and blob files shared by all readers and the writer.
+pub(super) struct LogFiles {
+    path: PathBuf,
+    /// Log files with smaller id are immutable.
+    active_file_id: AtomicU64,
+    /// Log files with smaller id have been removed by compaction.
+    safe_file_id: AtomicU64,
+    /// Blob files with smaller id are immutable, which are all immutable in read-only mode.
+    active_blob_file_id: AtomicU64,
+    /// The number of blob files removed, so that readers close the readers of them.
+    removed_blob_files: AtomicU64,
+    /// The memory mappings of immutable log files and blob files.
+    mmaps: Mutex<HashMap<(FileKind, u64), Arc<Mmap>>>,
+}
+
+impl LogFiles {
+    /// Creates the shared state of log files in the given path.
+    pub(super) fn new(path: PathBuf, active_file_id: u64) -> LogFiles {
+        LogFiles {
+            path,
+            active_file_id: AtomicU64::new(active_file_id),
+            safe_file_id: AtomicU64::new(0),
+            active_blob_file_id: AtomicU64::new(u64::MAX),
+            removed_blob_files: AtomicU64::new(0),
+            mmaps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Marks the log files with id less than `file_id` as immutable.
+    pub(super) fn set_active_file_id(&self, file_id: u64) {
+        self.active_file_id.store(file_id, Ordering::SeqCst);
+    }
+
+    /// Marks the log files with id less than `file_id` as removed.
+    ///
+    /// Their mappings are dropped, and readers will close them on their next read.
+    pub(super) fn set_safe_file_id(&self, file_id: u64) {
+        self.safe_file_id.store(file_id, Ordering::SeqCst);
+        self.mmaps
+            .lock()
+            .unwrap()
+            .retain(|&(kind, mapped_file_id), _| {
+                kind != FileKind::Log || mapped_file_id >= file_id
+            });
+    }
+
+    /// Marks the blob files with id less than `file_id` as immutable.
+    pub(super) fn set_active_blob_file_id(&self, file_id: u64) {
+        self.active_blob_file_id.store(file_id, Ordering::SeqCst);
+    }
+
+    /// Marks a blob file as removed.
+    ///
+    /// Its mapping is dropped, and readers will close all their blob files on their next read.
+    pub(super) fn remove_blob_file(&self, file_id: u64) {
+        self.mmaps
+            .lock()
+            .unwrap()
+            .remove(&(FileKind::Blob, file_id));
+        self.removed_blob_files.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn is_immutable(&self, kind: FileKind, file_id: u64) -> bool {
+        let active_file_id = match kind {
+            FileKind::Log => &self.active_file_id,
+            FileKind::Blob => &self.active_blob_file_id,
+        };
+        file_id < active_file_id.load(Ordering::SeqCst)
+    }
+
+    fn file_path(&self, kind: FileKind, file_id: u64) -> PathBuf {
+        match kind {
+            FileKind::Log => KvStore::log_path(&self.path, file_id),
+            FileKind::Blob => blob::blob_path(&self.path, file_id),
+        }
+    }
+
+    /// Gets the mapping of an immutable file, which is shared by all readers.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn mmap(&self, kind: FileKind, file_id: u64) -> Result<Arc<Mmap>> {
+        let mut mmaps = self.mmaps.lock().unwrap();
+        if let Some(mmap) = mmaps.get(&(kind, file_id)) {
+            return Ok(Arc::clone(mmap));
+        }
+
+        let file = File::open(self.file_path(kind, file_id))?;
+        // It is safe, because immutable files are never modified, and removing them
+        // by compaction does not invalidate the mapping.
+        let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+        mmaps.insert((kind, file_id), Arc::clone(&mmap));
+        Ok(mmap)
+    }
+}
+
+/// The reader of a log file or a blob file.
+enum Reader {
+    /// Immutable files are read through the shared memory mapping.
+    Mapped(Arc<Mmap>),
+    /// The active file is read through a buffered file reader.
+    Buffered(BufReader<File>),
+}
+
+/// The cache of log file and blob file readers owned by each `KvStore` handle.
+///
+/// It keeps at most `capacity` readers. When the cache is full,
+/// the least recently used reader is closed and will be reopened on demand.
+pub(super) struct ReaderCache {
+    files: Arc<LogFiles>,
+    readers: LruCache<(FileKind, u64), Reader>,
+    safe_file_id: u64,
+    removed_blob_files: u64,
+}
+
+impl ReaderCache {
+    /// Creates a reader cache of the given log files.
+    ///
+    /// At least one reader is kept open, even if `capacity` is 0.
+    pub(super) fn new(files: Arc<LogFiles>, capacity: usize) -> ReaderCache {
+        ReaderCache {
+            files,
+            readers: LruCache::new(capacity.max(1)),
+            safe_file_id: 0,
+            removed_blob_files: 0,
+        }
+    }
+
+    pub(super) fn path(&self) -> &PathBuf {
+        self.files.path()
+    }
+
+    /// Gets a reader of the raw command pointed by `pointer`.
+    ///
+    /// Reading from an immutable log file needs no system call.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns an I/O error of `UnexpectedEof` kind
+    /// if the pointer is beyond the end of a truncated or corrupted log file.
+    pub(super) fn command_reader(&mut self, pointer: &LogPointer) -> Result<Box<dyn Read + '_>> {
+        self.record_reader(FileKind::Log, pointer.file_id, pointer.offset, pointer.len)
+    }
+
+    /// Gets a reader of the raw record pointed by `blob`.
+    ///
+    /// Reading from an immutable blob file needs no system call.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns an I/O error of `UnexpectedEof` kind
+    /// if the pointer is beyond the end of a truncated or corrupted blob file.
+    pub(super) fn blob_reader(&mut self, blob: &BlobPointer) -> Result<Box<dyn Read + '_>> {
+        self.record_reader(FileKind::Blob, blob.file_id, blob.offset, blob.len)
+    }
+
+    fn record_reader(
+        &mut self,
+        kind: FileKind,
+        file_id: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<Box<dyn Read + '_>> {
+        match self.get(kind, file_id)? {
+            Reader::Mapped(mmap) => {
+                let mmap: &Mmap = mmap;
+                let start = offset as usize;
+                let end = start.checked_add(len as usize);
+                match end.and_then(|end| mmap.get(start..end)) {
+                    Some(record) => Ok(Box::new(record)),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("pointer is beyond the end of {:?} file {}", kind, file_id),
+                    )
+                    .into()),
+                }
+            }
+            Reader::Buffered(reader) => {
+                reader.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(reader.take(len)))
+            }
+        }
+    }
+
+    /// Gets the reader of the log file ("path/<file_id>.log") or blob file.
+    ///
+    /// The file is opened, if it is not in the cache,
+    /// or it has become immutable since it was opened.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn get(&mut self, kind: FileKind, file_id: u64) -> Result<&mut Reader> {
+        self.remove_stale_readers();
+
+        let immutable = self.files.is_immutable(kind, file_id);
+        let reopen = match self.readers.peek(&(kind, file_id)) {
+            Some(Reader::Mapped(_)) => false,
+            Some(Reader::Buffered(_)) => immutable,
+            None => true,
+        };
+        if reopen {
+            let reader = if immutable {
+                Reader::Mapped(self.files.mmap(kind, file_id)?)
+            } else {
+                let file = File::open(self.files.file_path(kind, file_id))?;
+                Reader::Buffered(BufReader::new(file))
+            };
+            self.readers.put((kind, file_id), reader);
+        }
+        Ok(self
+            .readers
+            .get_mut(&(kind, file_id))
+            .expect("reader is just inserted"))
+    }
+
+    /// Closes the readers of log files removed by compaction,
+    /// and of all blob files if any blob file is removed.
+    fn remove_stale_readers(&mut self) {
+        let safe_file_id = self.files.safe_file_id.load(Ordering::SeqCst);
+        let removed_blob_files = self.files.removed_blob_files.load(Ordering::SeqCst);
+        if safe_file_id > self.safe_file_id || removed_blob_files > self.removed_blob_files {
+            let stale_readers: Vec<(FileKind, u64)> = self
+                .readers
+                .iter()
+                .map(|(&key, _)| key)
+                .filter(|&(kind, file_id)| match kind {
+                    FileKind::Log => file_id < safe_file_id,
+                    FileKind::Blob => removed_blob_files > self.removed_blob_files,
+                })
+                .collect();
+            for key in stale_readers {
+                self.readers.pop(&key);
+            }
+            self.safe_file_id = safe_file_id;
+            self.removed_blob_files = removed_blob_files;
+        }
+    }
+}
+
+impl Clone for ReaderCache {
+    /// Creates an empty reader cache of the same log files.
+    fn clone(&self) -> ReaderCache {
+        ReaderCache::new(Arc::clone(&self.files), self.readers.cap())
+    }
+}
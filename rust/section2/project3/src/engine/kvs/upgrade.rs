@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use super::blob::BLOB_FILE_EX;
+use super::lock::DirLock;
+use super::{
+    Command, KvStore, KvStoreOptions, COMPACTION_FILE_EX, DATA_FILE_EX, FORMAT_VERSION,
+    JSON_FORMAT_VERSION,
+};
+use crate::engine::manifest::Manifest;
+use crate::{KvsEngine, KvsError, Result};
+
+/// The directory keeping JSON log files after they are upgraded.
+const JSON_BACKUP_DIRNAME: &str = "json-backup";
+/// The directory JSON log files are moved into, before all of them are moved.
+const JSON_BACKUP_TMP_DIRNAME: &str = "json-backup.tmp";
+/// The directory the upgraded store is written into, before it is moved into the data directory.
+const UPGRADE_DIRNAME: &str = "upgrade.tmp";
+
+impl KvStore {
+    /// Upgrades the data directory in the given path from the JSON format into the current
+    /// format, which must be done offline.
+    ///
+    /// The JSON log files are moved into the `json-backup` directory in the path,
+    /// which can be removed after the upgrade. An interrupted upgrade is restarted from them.
+    ///
+    /// It does nothing, if the data directory is already in the current format.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors, or returns `KvsError::Locked` error
+    /// if the data directory is opened by another process.
+    pub fn upgrade(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<()> {
+        let path = path.into();
+        let _lock = DirLock::lock(&path)?;
+        if Manifest::load(&path)?.is_some() || Self::legacy_format_version(&path)? == FORMAT_VERSION
+        {
+            return Ok(());
+        }
+
+        // Move JSON log files away first, so that they are never mixed with the upgraded ones.
+        let backup_path = path.join(JSON_BACKUP_DIRNAME);
+        if backup_path.exists() {
+            Self::remove_data_files(&path)?;
+        } else {
+            let backup_tmp_path = path.join(JSON_BACKUP_TMP_DIRNAME);
+            fs::create_dir_all(&backup_tmp_path)?;
+            for file_id in Self::sorted_file_ids(&path)? {
+                fs::rename(
+                    Self::log_path(&path, file_id),
+                    Self::log_path(&backup_tmp_path, file_id),
+                )?;
+            }
+            fs::rename(backup_tmp_path, &backup_path)?;
+        }
+
+        let mut pairs = BTreeMap::new();
+        for file_id in Self::sorted_file_ids(&backup_path)? {
+            let reader = BufReader::new(File::open(Self::log_path(&backup_path, file_id))?);
+            for cmd in serde_json::Deserializer::from_reader(reader).into_iter::<Command>() {
+                match cmd? {
+                    Command::Set { key, value } => {
+                        pairs.insert(key, value);
+                    }
+                    Command::Remove { key } => {
+                        pairs.remove(&key);
+                    }
+                    _ => return Err(KvsError::UnexpectedCommandType),
+                }
+            }
+        }
+
+        let upgrade_path = path.join(UPGRADE_DIRNAME);
+        if upgrade_path.exists() {
+            fs::remove_dir_all(&upgrade_path)?;
+        }
+        let mut store = KvStore::open_with_options(&upgrade_path, options)?;
+        for (key, value) in pairs {
+            store.set(key, value)?;
+        }
+        drop(store);
+
+        // The data directory is not opened until its manifest is moved at last.
+        let manifest = Manifest::load(&upgrade_path)?.expect("manifest is created by open");
+        for entry in fs::read_dir(&upgrade_path)? {
+            let entry_path = entry?.path();
+            if Self::is_data_file(&entry_path) {
+                let file_name = entry_path.file_name().expect("data file has a name");
+                fs::rename(&entry_path, path.join(file_name))?;
+            }
+        }
+        manifest.store(&path)?;
+        fs::remove_dir_all(upgrade_path)?;
+
+        Ok(())
+    }
+
+    /// Detects the format version of the log files in a data directory without manifest.
+    ///
+    /// The directory of an interrupted upgrade is in the JSON format, since it must be
+    /// upgraded again.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn legacy_format_version(path: &Path) -> Result<u32> {
+        if path.join(JSON_BACKUP_DIRNAME).exists() || path.join(JSON_BACKUP_TMP_DIRNAME).exists() {
+            return Ok(JSON_FORMAT_VERSION);
+        }
+
+        for file_id in Self::sorted_file_ids(path)? {
+            let mut first_byte = [0; 1];
+            if File::open(Self::log_path(path, file_id))?.read(&mut first_byte)? > 0 {
+                // A JSON command is an object, while a CBOR command is a map starting with 0xa1.
+                return Ok(if first_byte[0] == b'{' {
+                    JSON_FORMAT_VERSION
+                } else {
+                    FORMAT_VERSION
+                });
+            }
+        }
+        Ok(FORMAT_VERSION)
+    }
+
+    /// Removes the log and blob files of an interrupted upgrade.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn remove_data_files(path: &Path) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            if Self::is_data_file(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_data_file(path: &Path) -> bool {
+        path.is_file()
+            && [DATA_FILE_EX, BLOB_FILE_EX, COMPACTION_FILE_EX]
+                .iter()
+                .any(|&ex| path.extension() == Some(OsStr::new(ex)))
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::blob::BlobWriter;
+use super::cache::ValueCache;
+use super::reader::{LogFiles, ReaderCache};
+use super::{Command, Encryption, KvStore, KvStoreOptions, LogPointer};
+use crate::{KvsError, Result};
+
+/// The writer of a `KvStore`, which is shared by all handles of it.
+///
+/// It appends commands into the active log file, and compacts log files and blob files.
+pub(super) struct KvStoreWriter {
+    files: Arc<LogFiles>,
+    writer: BufWriter<File>,
+    blobs: BlobWriter,
+    readers: ReaderCache,
+    index: Arc<RwLock<BTreeMap<String, LogPointer>>>,
+    cache: Arc<ValueCache>,
+    cur_file_id: u64,
+    /// The number of stale bytes in each log file.
+    stale: BTreeMap<u64, u64>,
+    uncompacted: u64,
+    /// The number of log compactions since the store is opened.
+    compactions: u64,
+    /// The total duration of the log compactions.
+    compaction_duration: Duration,
+    /// The blob files which are at least half stale.
+    collectable_blob_files: BTreeSet<u64>,
+    options: KvStoreOptions,
+}
+
+impl KvStoreWriter {
+    /// Creates a writer appending into the log file with id `cur_file_id`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn new(
+        files: Arc<LogFiles>,
+        blobs: BlobWriter,
+        index: Arc<RwLock<BTreeMap<String, LogPointer>>>,
+        cache: Arc<ValueCache>,
+        cur_file_id: u64,
+        stale: BTreeMap<u64, u64>,
+        options: KvStoreOptions,
+    ) -> Result<KvStoreWriter> {
+        let writer = KvStore::new_log_writer(files.path(), cur_file_id)?;
+        let readers = ReaderCache::new(Arc::clone(&files), options.max_open_files);
+        let uncompacted = stale.values().sum();
+        Ok(KvStoreWriter {
+            files,
+            writer,
+            blobs,
+            readers,
+            index,
+            cache,
+            cur_file_id,
+            stale,
+            uncompacted,
+            compactions: 0,
+            compaction_duration: Duration::from_secs(0),
+            collectable_blob_files: BTreeSet::new(),
+            options,
+        })
+    }
+
+    /// Returns the number of stale bytes in log files, which compaction reclaims.
+    pub(super) fn uncompacted(&self) -> u64 {
+        self.uncompacted
+    }
+
+    /// Returns the number of log compactions since the store is opened, and their total duration.
+    pub(super) fn compactions(&self) -> (u64, Duration) {
+        (self.compactions, self.compaction_duration)
+    }
+
+    /// Sets the value of `key` with a string `value`.
+    ///
+    /// Values not smaller than `blob_threshold` are written into the active blob file,
+    /// and the log only refers to them.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors.
+    pub(super) fn set(&mut self, key: String, value: String) -> Result<()> {
+        // Use cloned key, because key is stored in command and index.
+        let cmd = Command::set(key.clone(), value, self.options.compression)?;
+        let pointer = match self.options.blob_threshold {
+            Some(threshold) if cmd.value_len() >= threshold => {
+                let mut record = Vec::new();
+                cmd.encrypt(self.options.encryption.as_ref())?
+                    .write_to(&mut record)?;
+                let blob = self.blobs.append(&record, self.options.max_file_size)?;
+                let pointer = self.append(Command::SetBlob {
+                    key: key.clone(),
+                    blob,
+                })?;
+                LogPointer {
+                    blob: Some(blob),
+                    ..pointer
+                }
+            }
+            _ => self.append(cmd)?,
+        };
+
+        let old_pointer = self.index.write().unwrap().insert(key.clone(), pointer);
+        self.cache.remove(&key);
+        if let Some(old_pointer) = old_pointer {
+            self.mark_stale(&old_pointer);
+        }
+
+        self.maybe_compact()
+    }
+
+    /// Removes a given `key`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key does not exist.
+    pub(super) fn remove(&mut self, key: String) -> Result<()> {
+        if !self.index.read().unwrap().contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+
+        let cmd = Command::Remove { key: key.clone() };
+        let pointer = self.append(cmd)?;
+
+        // The remove command itself is never read again.
+        self.mark_stale(&pointer);
+        let old_pointer = self.index.write().unwrap().remove(&key);
+        self.cache.remove(&key);
+        if let Some(old_pointer) = old_pointer {
+            self.mark_stale(&old_pointer);
+        }
+
+        self.maybe_compact()
+    }
+
+    /// Syncs the active log file and blob file to disk.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        self.blobs.sync()
+    }
+
+    /// Appends the command into the active log file and returns the pointer of it.
+    ///
+    /// The command is encrypted, if encryption is enabled.
+    /// Rotates the active log file, if its size exceeds `max_file_size` after appending.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, serialization or encryption errors.
+    fn append(&mut self, cmd: Command) -> Result<LogPointer> {
+        let cmd = cmd.encrypt(self.options.encryption.as_ref())?;
+        let offset = self.writer.seek(SeekFrom::End(0))?;
+        cmd.write_to(&mut self.writer)?;
+        self.writer.flush()?;
+
+        let cur_offset = self.writer.seek(SeekFrom::End(0))?;
+        let pointer = LogPointer {
+            offset,
+            len: cur_offset - offset,
+            file_id: self.cur_file_id,
+            blob: None,
+        };
+
+        if cur_offset >= self.options.max_file_size {
+            // The immutable file is synced once, so that `sync` only syncs the active one.
+            self.writer.get_ref().sync_all()?;
+            self.cur_file_id += 1;
+            self.writer = KvStore::new_log_writer(self.files.path(), self.cur_file_id)?;
+            self.files.set_active_file_id(self.cur_file_id);
+        }
+
+        Ok(pointer)
+    }
+
+    /// Records the command pointed by `pointer` and its value in a blob file as stale.
+    fn mark_stale(&mut self, pointer: &LogPointer) {
+        *self.stale.entry(pointer.file_id).or_insert(0) += pointer.len;
+        self.uncompacted += pointer.len;
+        if let Some(blob) = &pointer.blob {
+            if let Some(file_id) = self.blobs.mark_stale(blob) {
+                self.collectable_blob_files.insert(file_id);
+            }
+        }
+    }
+
+    /// Collects blob files which are at least half stale,
+    /// and compacts log files, if the stale bytes reach the compaction threshold.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, compression or encryption errors.
+    fn maybe_compact(&mut self) -> Result<()> {
+        let collectable_blob_files = mem::take(&mut self.collectable_blob_files);
+        let threshold = match self.options.compaction_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+
+        for file_id in collectable_blob_files {
+            self.collect_blob_file(file_id)?;
+        }
+        if self.uncompacted >= threshold {
+            let started = Instant::now();
+            self.compact(threshold)?;
+            self.compactions += 1;
+            self.compaction_duration += started.elapsed();
+        }
+        Ok(())
+    }
+
+    /// Moves the live values of a blob file into the active blob file, and removes it.
+    ///
+    /// The moved values are referred by new `SetBlob` commands appended into the log,
+    /// so that no log file is rewritten.
+    /// Values compressed or encrypted by another algorithm are encoded again like `set`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, serialization, compression or encryption errors.
+    fn collect_blob_file(&mut self, file_id: u64) -> Result<()> {
+        let live_pointers: Vec<(String, LogPointer)> = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, pointer)| pointer.blob.map(|blob| blob.file_id) == Some(file_id))
+            .map(|(key, pointer)| (key.clone(), *pointer))
+            .collect();
+
+        let mut buf = Vec::new();
+        let mut record = Vec::new();
+        for (key, pointer) in live_pointers {
+            let old_blob = pointer.blob.expect("pointer refers to a blob");
+            buf.clear();
+            self.readers.blob_reader(&old_blob)?.read_to_end(&mut buf)?;
+            record.clear();
+            write_record(&buf, &self.options, &mut record)?;
+            let blob = self.blobs.append(&record, self.options.max_file_size)?;
+            let new_pointer = self.append(Command::SetBlob {
+                key: key.clone(),
+                blob,
+            })?;
+
+            self.index.write().unwrap().insert(
+                key.clone(),
+                LogPointer {
+                    blob: Some(blob),
+                    ..new_pointer
+                },
+            );
+            self.cache.remove(&key);
+            // The old value is removed with its blob file below.
+            self.mark_stale(&LogPointer {
+                blob: None,
+                ..pointer
+            });
+        }
+
+        // The copied blobs and their new pointers must be durable before the old file is gone.
+        self.sync()?;
+        // No reader uses the blob file since the index is updated.
+        self.blobs.remove_file(file_id)
+    }
+
+    /// Compacts the oldest log files into one file.
+    ///
+    /// Merges the oldest immutable log files, until their stale bytes reach `threshold`,
+    /// so that the newer files which are mostly live are not rewritten again.
+    ///
+    /// Writes the live commands of the merged files into the log file with id
+    /// `self.cur_file_id + 1`, and changes current file with id from `self.cur_file_id`
+    /// to `self.cur_file_id + 2`.
+    /// The compacted file only contains keys which are not touched by the unmerged files,
+    /// so it is safe to load it after them.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O, compression or encryption errors.
+    fn compact(&mut self, threshold: u64) -> Result<()> {
+        let path = self.files.path().clone();
+        let compaction_file_id = self.cur_file_id + 1;
+        self.cur_file_id += 2;
+        self.writer = KvStore::new_log_writer(&path, self.cur_file_id)?;
+        self.files.set_active_file_id(self.cur_file_id);
+
+        let mut merged_file_ids = Vec::new();
+        let mut merged_stale = 0;
+        for file_id in KvStore::sorted_file_ids(&path)? {
+            if file_id >= compaction_file_id || merged_stale >= threshold {
+                break;
+            }
+            merged_stale += self.stale.get(&file_id).unwrap_or(&0);
+            merged_file_ids.push(file_id);
+        }
+        let last_merged_file_id = match merged_file_ids.last() {
+            Some(&file_id) => file_id,
+            None => return Ok(()),
+        };
+
+        // Write into a temporary file first, so that a partially compacted file is never loaded.
+        let compaction_path = KvStore::compaction_path(&path, compaction_file_id);
+        let mut compaction_writer = BufWriter::new(File::create(&compaction_path)?);
+        let mut new_pointers = Vec::new();
+        let mut pre_offset = 0;
+
+        let mut buf = Vec::new();
+
+        // Readers are not blocked, since only the writer modifies the index.
+        for pointer in self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .filter(|pointer| pointer.file_id <= last_merged_file_id)
+        {
+            buf.clear();
+            self.readers
+                .command_reader(pointer)?
+                .read_to_end(&mut buf)?;
+
+            // Values in blob files are kept as they are, and encoded again when they are collected.
+            write_record(&buf, &self.options, &mut compaction_writer)?;
+
+            let cur_offset = compaction_writer.stream_position()?;
+            new_pointers.push(LogPointer {
+                offset: pre_offset,
+                len: cur_offset - pre_offset,
+                file_id: compaction_file_id,
+                blob: pointer.blob,
+            });
+            pre_offset = cur_offset;
+        }
+
+        // The compacted file and its name must be durable before the merged files are removed.
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_all()?;
+        fs::rename(
+            &compaction_path,
+            KvStore::log_path(&path, compaction_file_id),
+        )?;
+        KvStore::sync_dir(&path)?;
+
+        for ((key, pointer), new_pointer) in self
+            .index
+            .write()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, pointer)| pointer.file_id <= last_merged_file_id)
+            .zip(new_pointers)
+        {
+            *pointer = new_pointer;
+            self.cache.remove(key);
+        }
+
+        // No reader uses the merged files since the index is updated.
+        self.files.set_safe_file_id(last_merged_file_id + 1);
+        // Remove from the oldest one, so that a removed key never reappears after a crash.
+        for file_id in merged_file_ids {
+            fs::remove_file(KvStore::log_path(&path, file_id))?;
+            if let Some(stale) = self.stale.remove(&file_id) {
+                self.uncompacted -= stale;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the raw record of a command into `out`.
+///
+/// The record is written as it is, unless its value is compressed or encrypted by another
+/// algorithm than `options`, in which case it is decoded and encoded again like `set`.
+///
+/// # Errors
+///
+/// It propagates I/O, serialization, compression or encryption errors.
+fn write_record(record: &[u8], options: &KvStoreOptions, out: &mut impl Write) -> Result<()> {
+    let cmd: Command = serde_cbor::from_slice(record)?;
+    let encryption = options.encryption.as_ref();
+    let cur_cipher = cmd.cipher();
+    let cmd = cmd.decrypt(encryption)?;
+    if cur_cipher == encryption.map(Encryption::cipher)
+        && !cmd.needs_recompression(options.compression)
+    {
+        out.write_all(record)?;
+        return Ok(());
+    }
+
+    let cmd = if cmd.needs_recompression(options.compression) {
+        let (key, value) = cmd.into_key_value()?;
+        Command::set(key, value, options.compression)?
+    } else {
+        cmd
+    };
+    cmd.encrypt(encryption)?.write_to(out)
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+const MANIFEST_FILENAME: &str = "MANIFEST";
+const MANIFEST_TMP_FILENAME: &str = "MANIFEST.tmp";
+
+/// The manifest of a data directory, which records the on-disk format of the store in it.
+///
+/// It is stored as "<name> = <value>" lines in the `MANIFEST` file, such as:
+///
+/// ```text
+/// format_version = 2
+/// engine = kvs
+/// compression = none
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Manifest {
+    engine: String,
+    format_version: u32,
+    /// The options which the store is opened with for the last time.
+    options: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Creates a manifest of the given engine and format version.
+    pub(crate) fn new(engine: &str, format_version: u32) -> Manifest {
+        Manifest {
+            engine: engine.to_owned(),
+            format_version,
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Records an option.
+    pub(crate) fn option(mut self, name: &str, value: impl Display) -> Manifest {
+        self.options.insert(name.to_owned(), value.to_string());
+        self
+    }
+
+    /// Gets a recorded option.
+    pub(crate) fn get_option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    /// Loads the manifest in the given path.
+    ///
+    /// Returns `None`, if the `MANIFEST` file does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `KvsError::IncompatibleFormat` error
+    /// if the file is malformed.
+    pub(crate) fn load(path: &Path) -> Result<Option<Manifest>> {
+        let manifest_path = path.join(MANIFEST_FILENAME);
+        if !manifest_path.exists() {
+            return Ok(None);
+        }
+
+        let mut engine = None;
+        let mut format_version = None;
+        let mut options = BTreeMap::new();
+        for line in fs::read_to_string(manifest_path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(Self::malformed(line)),
+            };
+            match name {
+                "engine" => engine = Some(value.to_owned()),
+                "format_version" => {
+                    format_version = Some(value.parse().map_err(|_| Self::malformed(line))?)
+                }
+                _ => {
+                    options.insert(name.to_owned(), value.to_owned());
+                }
+            }
+        }
+
+        match (engine, format_version) {
+            (Some(engine), Some(format_version)) => Ok(Some(Manifest {
+                engine,
+                format_version,
+                options,
+            })),
+            _ => Err(KvsError::IncompatibleFormat(
+                "MANIFEST misses the engine or the format version".to_owned(),
+            )),
+        }
+    }
+
+    /// Stores the manifest into the given path.
+    ///
+    /// The manifest is written into a temporary file first,
+    /// so that a partially written manifest is never loaded.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    pub(crate) fn store(&self, path: &Path) -> Result<()> {
+        let mut content = format!(
+            "format_version = {}\nengine = {}\n",
+            self.format_version, self.engine
+        );
+        for (name, value) in &self.options {
+            content.push_str(&format!("{} = {}\n", name, value));
+        }
+
+        let tmp_path = path.join(MANIFEST_TMP_FILENAME);
+        fs::write(&tmp_path, content)?;
+        fs::rename(tmp_path, path.join(MANIFEST_FILENAME))?;
+        Ok(())
+    }
+
+    /// Checks whether the store can be opened by the given engine and format version.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongEngineType` error if the store is written by another engine,
+    /// or `KvsError::IncompatibleFormat` error if the store is in another format version.
+    pub(crate) fn check(&self, engine: &str, format_version: u32) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvsError::WrongEngineType);
+        }
+        if self.format_version > format_version {
+            return Err(KvsError::IncompatibleFormat(format!(
+                "format version {} is newer than the supported version {}",
+                self.format_version, format_version
+            )));
+        }
+        if self.format_version < format_version {
+            return Err(KvsError::IncompatibleFormat(format!(
+                "format version {} is older than the supported version {}, \
+                 upgrade it by `kvs-upgrade` first",
+                self.format_version, format_version
+            )));
+        }
+        Ok(())
+    }
+
+    fn malformed(line: &str) -> KvsError {
+        KvsError::IncompatibleFormat(format!("MANIFEST has a malformed line: {}", line))
+    }
+}
@@ -1,6 +1,12 @@
-use crate::{KvsEngine, KvsError, Result};
+use super::manifest::Manifest;
+use crate::{EngineStats, KvsEngine, KvsError, Result};
+
+const ENGINE_NAME: &str = "sled";
+/// The on-disk format of sled 0.31.
+const FORMAT_VERSION: u32 = 1;
 
 /// The implementation for `KvsEngine` for the sled storage engine.
+#[derive(Clone)]
 pub struct SledKvsEngine {
     tree: sled::Db,
 }
@@ -11,10 +17,27 @@ impl SledKvsEngine {
     /// # Errors
     ///
     /// It propagates sled errors.
+    ///
+    /// It returns `KvsError::WrongEngineType` or `KvsError::IncompatibleFormat` error,
+    /// if the data directory is written by another engine or in another format.
     pub fn open(path: &std::path::Path) -> Result<SledKvsEngine> {
+        if let Some(manifest) = Manifest::load(path)? {
+            manifest.check(ENGINE_NAME, FORMAT_VERSION)?;
+        }
         let tree = sled::open(path)?;
+        Manifest::new(ENGINE_NAME, FORMAT_VERSION).store(path)?;
         Ok(SledKvsEngine { tree })
     }
+
+    /// Refuses to open a sled db in read-only mode, since sled 0.31 can not open its files
+    /// without writing its recovery state.
+    ///
+    /// # Errors
+    ///
+    /// It always returns `KvsError::ReadOnlyUnsupported` error, without touching the path.
+    pub fn open_read_only(_path: &std::path::Path) -> Result<SledKvsEngine> {
+        Err(KvsError::ReadOnlyUnsupported)
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
@@ -38,4 +61,30 @@ impl KvsEngine for SledKvsEngine {
         self.tree.flush()?;
         Ok(())
     }
+
+    fn flush(&mut self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.tree
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    /// Gets the statistics of the db, where sled compacts its own files.
+    fn engine_stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            name: ENGINE_NAME.to_owned(),
+            keys: self.tree.len() as u64,
+            disk_size: self.tree.size_on_disk()?,
+            uncompacted_bytes: None,
+            log_files: None,
+            compactions: None,
+            compaction_duration: None,
+        })
+    }
 }
@@ -1,47 +1,144 @@
+use std::fmt;
 use std::io;
 use std::string;
 
 use failure::Fail;
 
+use crate::ErrorCode;
+
 /// Error type for kvs.
-#[derive(Fail, Debug)]
-#[fail(display = "Error for kvs")]
+#[derive(Debug)]
 pub enum KvsError {
     /// Io error.
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    Io(io::Error),
 
     /// Serialization for bincode or deserialization error.
-    #[fail(display = "{}", _0)]
-    Bincode(#[cause] bincode::Error),
+    Bincode(bincode::Error),
+
+    /// Deserialization error of the JSON log format.
+    Json(serde_json::Error),
 
     /// Serialization for serde_cbor or deserialization error.
-    #[fail(display = "{}", _0)]
-    Cbor(#[cause] serde_cbor::Error),
+    Cbor(serde_cbor::Error),
 
     /// Key or value is invalid UTF-8 sequence
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] string::FromUtf8Error),
+    Utf8(string::FromUtf8Error),
 
     /// Sled error.
-    #[fail(display = "{}", _0)]
-    Sled(#[cause] sled::Error),
+    Sled(sled::Error),
 
     /// Key not found error.
-    #[fail(display = "Key not found")]
     KeyNotFound,
 
     /// Key not found error.
-    #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
 
     /// Key not found error.
-    #[fail(display = "Different engine type from the previous one")]
     WrongEngineType,
 
-    /// Error occurring in remote with a string error message.
-    #[fail(display = "Error occurring in remote")]
-    RemoteError(String),
+    /// A log record can not be encrypted.
+    Encryption,
+
+    /// A log record can not be decrypted, because the encryption key is wrong or missing,
+    /// or the record is corrupted.
+    Decryption,
+
+    /// Encryption key is not 64 hex digits.
+    InvalidEncryptionKey,
+
+    /// Encryption is not supported by the engine.
+    EncryptionUnsupported,
+
+    /// Read-only mode is not supported by the engine.
+    ReadOnlyUnsupported,
+
+    /// The store is opened in read-only mode.
+    ReadOnly,
+
+    /// The data directory is locked by another process.
+    Locked,
+
+    /// The data directory is in an incompatible format.
+    IncompatibleFormat(String),
+
+    /// The server and the client do not support the same protocol.
+    ProtocolMismatch(String),
+
+    /// The server rejects the connection, because it serves too many connections.
+    ServerBusy,
+
+    /// The server does not respond in time.
+    Timeout,
+
+    /// The server rejects the credentials of the client, or requires them.
+    AuthenticationFailed,
+
+    /// The user is not permitted to send the request.
+    PermissionDenied(String),
+
+    /// The access control list is invalid.
+    InvalidAcl(String),
+
+    /// TLS error, e.g. an invalid certificate or a failed TLS handshake.
+    Tls(String),
+
+    /// Error occurring in remote with its error code and message.
+    RemoteError(ErrorCode, String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Io(e) => write!(f, "{}", e),
+            KvsError::Bincode(e) => write!(f, "{}", e),
+            KvsError::Json(e) => write!(f, "{}", e),
+            KvsError::Cbor(e) => write!(f, "{}", e),
+            KvsError::Utf8(e) => write!(f, "UTF-8 error: {}", e),
+            KvsError::Sled(e) => write!(f, "{}", e),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::UnexpectedCommandType => write!(f, "Unexpected command type"),
+            KvsError::WrongEngineType => write!(f, "Different engine type from the previous one"),
+            KvsError::Encryption => write!(f, "Failed to encrypt log record"),
+            KvsError::Decryption => write!(
+                f,
+                "Failed to decrypt log record, the encryption key may be wrong"
+            ),
+            KvsError::InvalidEncryptionKey => write!(f, "Encryption key must be 64 hex digits"),
+            KvsError::EncryptionUnsupported => {
+                write!(f, "Encryption is not supported by the engine")
+            }
+            KvsError::ReadOnlyUnsupported => {
+                write!(f, "Read-only mode is not supported by the engine")
+            }
+            KvsError::ReadOnly => write!(f, "Store is read-only"),
+            KvsError::Locked => write!(f, "Data directory is locked by another process"),
+            KvsError::IncompatibleFormat(e) => write!(f, "Incompatible data directory: {}", e),
+            KvsError::ProtocolMismatch(e) => write!(f, "Protocol mismatch: {}", e),
+            KvsError::ServerBusy => write!(f, "Server busy"),
+            KvsError::Timeout => write!(f, "Request timed out"),
+            KvsError::AuthenticationFailed => write!(f, "Authentication failed"),
+            KvsError::PermissionDenied(e) => write!(f, "Permission denied: {}", e),
+            KvsError::InvalidAcl(e) => write!(f, "Invalid ACL: {}", e),
+            KvsError::Tls(e) => write!(f, "TLS error: {}", e),
+            KvsError::RemoteError(code, message) => {
+                write!(f, "Error occurring in remote ({:?}): {}", code, message)
+            }
+        }
+    }
+}
+
+impl Fail for KvsError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Bincode(e) => Some(e),
+            KvsError::Json(e) => Some(e),
+            KvsError::Cbor(e) => Some(e),
+            KvsError::Utf8(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -56,6 +153,12 @@ impl From<bincode::Error> for KvsError {
     }
 }
 
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> Self {
+        KvsError::Json(e)
+    }
+}
+
 impl From<serde_cbor::Error> for KvsError {
     fn from(e: serde_cbor::Error) -> Self {
         KvsError::Cbor(e)
@@ -68,6 +171,12 @@ impl From<string::FromUtf8Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(e: rustls::Error) -> Self {
+        KvsError::Tls(e.to_string())
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(e: sled::Error) -> Self {
         KvsError::Sled(e)
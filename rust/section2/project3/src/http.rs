@@ -0,0 +1,405 @@
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::server::{accept_tcp, DEFAULT_REQUEST_TIMEOUT};
+use crate::{ErrorCode, KvsEngine, KvsError, Result, ShutdownHandle, MAX_FRAME_LEN};
+
+/// The maximum length in bytes of the request line and headers.
+const MAX_HEAD_LEN: u64 = 64 * 1024;
+
+/// The kvs server speaking HTTP/JSON.
+///
+/// It serves these routes, and closes the connection after each response:
+///
+/// - `GET /kv/{key}` responds `{"key": ..., "value": ...}`.
+/// - `PUT /kv/{key}` sets the value in the body `{"value": ...}`.
+/// - `DELETE /kv/{key}` removes the key.
+/// - `GET /kv?start=&end=&limit=` lists the pairs with keys in `[start, end)` in ascending order.
+/// - `GET /health` responds `{"status": "ok"}`.
+///
+/// Errors are responded as `{"code": ..., "message": ...}`, with 404 status for
+/// `ErrorCode::KeyNotFound`.
+#[derive(Clone)]
+pub struct HttpServer<T: KvsEngine> {
+    addr: net::SocketAddr,
+    engine: T,
+    request_timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+}
+
+/// A key/value pair in a response.
+#[derive(Serialize, Debug)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// The body of `PUT /kv/{key}`.
+#[derive(Deserialize, Debug)]
+struct SetBody {
+    value: String,
+}
+
+/// The body of an error response.
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
+
+/// A parsed HTTP request.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    /// The percent-decoded path segments.
+    pub(crate) path: Vec<String>,
+    /// The percent-decoded query parameters.
+    pub(crate) query: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// An HTTP response with a JSON body, or a body of another content type.
+pub(crate) struct HttpResponse {
+    pub(crate) status: u16,
+    /// The content type and the body.
+    pub(crate) body: Option<(&'static str, String)>,
+}
+
+impl<T: KvsEngine + Clone + Send + 'static> HttpServer<T> {
+    /// Creates a new `HttpServer`.
+    pub fn new(addr: net::SocketAddr, engine: T) -> HttpServer<T> {
+        HttpServer {
+            addr,
+            engine,
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Sets the timeout of each read of a request and each write of its response,
+    /// or `None` to wait forever.
+    pub fn request_timeout(mut self, request_timeout: Option<Duration>) -> HttpServer<T> {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the handle to shut down this server, e.g. the one of the `KvsServer`
+    /// sharing the engine.
+    pub fn shutdown(mut self, shutdown: ShutdownHandle) -> HttpServer<T> {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Creates tcp server to listen on the given addr.
+    ///
+    /// It accepts connections in the main loop, and spawns a thread to serve one request
+    /// of each connection.
+    ///
+    /// It returns after it is shut down by its `ShutdownHandle`,
+    /// and the requests being served are responded.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of binding the address.
+    pub fn start(&mut self) -> Result<()> {
+        let listener = net::TcpListener::bind(self.addr)?;
+        let shutdown = self.shutdown.clone();
+        accept_tcp(listener, &shutdown, |stream| {
+            // The connection is served by a handle of the server with its own engine.
+            let mut server = self.clone();
+            Box::new(move || {
+                if let Err(e) = server.serve(stream) {
+                    warn!("HTTP connection error: {}", e);
+                }
+            })
+        })
+    }
+
+    /// Serves a request of the connection, and then closes it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn serve(&mut self, stream: net::TcpStream) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        stream.set_read_timeout(self.request_timeout)?;
+        stream.set_write_timeout(self.request_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+
+        let res = match read_request(&mut reader) {
+            Ok(Some(request)) => {
+                debug!(
+                    "Receive HTTP request from {} with {} {:?}",
+                    peer_addr, request.method, request.path
+                );
+                self.route(request)
+            }
+            Ok(None) => return Ok(()),
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {
+                error_response(400, ErrorCode::InvalidRequest, e.to_string())
+            }
+            Err(e) => return Err(e),
+        };
+        write_response(&mut writer, &res)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Dispatches a request to the engine by its method and path.
+    fn route(&mut self, request: HttpRequest) -> HttpResponse {
+        let path: Vec<&str> = request.path.iter().map(String::as_str).collect();
+        let res = match (request.method.as_str(), path.as_slice()) {
+            ("GET", ["health"]) => Ok(json_response(200, &serde_json::json!({"status": "ok"}))),
+            ("GET", ["kv"]) => self.list(&request.query),
+            ("GET", ["kv", key]) => match self.engine.get((*key).to_owned()) {
+                Ok(Some(value)) => Ok(json_response(
+                    200,
+                    &Pair {
+                        key: (*key).to_owned(),
+                        value,
+                    },
+                )),
+                Ok(None) => Err(KvsError::KeyNotFound),
+                Err(e) => Err(e),
+            },
+            ("PUT", ["kv", key]) => match serde_json::from_slice::<SetBody>(&request.body) {
+                Ok(body) => self
+                    .engine
+                    .set((*key).to_owned(), body.value)
+                    .map(|()| empty_response(204)),
+                Err(e) => {
+                    return error_response(400, ErrorCode::InvalidRequest, e.to_string());
+                }
+            },
+            ("DELETE", ["kv", key]) => self
+                .engine
+                .remove((*key).to_owned())
+                .map(|()| empty_response(204)),
+            (_, ["health"]) | (_, ["kv"]) | (_, ["kv", _]) => {
+                return error_response(
+                    405,
+                    ErrorCode::InvalidRequest,
+                    format!("Method {} is not allowed", request.method),
+                );
+            }
+            _ => {
+                return error_response(404, ErrorCode::InvalidRequest, "No such route".to_owned());
+            }
+        };
+
+        res.unwrap_or_else(|e| {
+            let code = ErrorCode::from(&e);
+            let status = match code {
+                ErrorCode::KeyNotFound => 404,
+                ErrorCode::ReadOnly => 403,
+                ErrorCode::InvalidRequest => 400,
+                _ => 500,
+            };
+            error_response(status, code, e.to_string())
+        })
+    }
+
+    /// Lists the pairs with keys in `[start, end)`, no more than `limit`.
+    fn list(&mut self, query: &[(String, String)]) -> Result<HttpResponse> {
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let limit = match param("limit").map(str::parse::<usize>) {
+            Some(Ok(limit)) => limit,
+            Some(Err(e)) => {
+                return Ok(error_response(
+                    400,
+                    ErrorCode::InvalidRequest,
+                    format!("Invalid limit: {}", e),
+                ));
+            }
+            None => usize::MAX,
+        };
+        let start = param("start").unwrap_or("");
+        let end = param("end");
+
+        let mut pairs = Vec::new();
+        for key in self.engine.keys()? {
+            if pairs.len() >= limit || end.is_some_and(|end| key.as_str() >= end) {
+                break;
+            }
+            if key.as_str() < start {
+                continue;
+            }
+            // The key may be removed after listing the keys.
+            if let Some(value) = self.engine.get(key.clone())? {
+                pairs.push(Pair { key, value });
+            }
+        }
+        Ok(json_response(200, &pairs))
+    }
+}
+
+/// Reads a request with its body of `Content-Length`.
+///
+/// Returns `None`, if the stream ends before the request.
+///
+/// # Errors
+///
+/// It propagates I/O errors, or returns an I/O error of `InvalidData` kind
+/// if the request is malformed.
+pub(crate) fn read_request(reader: &mut impl BufRead) -> Result<Option<HttpRequest>> {
+    let mut head = reader.take(MAX_HEAD_LEN);
+    let mut line = String::new();
+    if head.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned())
+        }
+        _ => return Err(bad_request("malformed request line")),
+    };
+
+    let mut content_len = 0;
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line)? == 0 {
+            return Err(bad_request("unexpected end of headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(bad_request("malformed header")),
+        };
+        if name.eq_ignore_ascii_case("Content-Length") {
+            content_len = value
+                .parse::<u32>()
+                .ok()
+                .filter(|&len| len <= MAX_FRAME_LEN)
+                .ok_or_else(|| bad_request("invalid Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return Err(bad_request("Transfer-Encoding is not supported"));
+        }
+    }
+
+    // The body grows as it arrives, rather than by the length the client claims.
+    let mut body = Vec::new();
+    reader.take(u64::from(content_len)).read_to_end(&mut body)?;
+    if body.len() < content_len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target.as_str(), ""),
+    };
+    let path = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| percent_decode(segment, false))
+        .collect::<Result<_>>()?;
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (k, v) = match param.find('=') {
+                Some(i) => (&param[..i], &param[i + 1..]),
+                None => (param, ""),
+            };
+            Ok((percent_decode(k, true)?, percent_decode(v, true)?))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        query,
+        body,
+    }))
+}
+
+/// Decodes `%XX` escapes of a path segment or query parameter.
+///
+/// `+` is decoded as a space only in a query parameter, where `plus_as_space` is true,
+/// and is literal in a path segment.
+fn percent_decode(s: &str, plus_as_space: bool) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| bad_request("invalid percent-encoding"))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| bad_request("invalid UTF-8 in percent-encoding"))
+}
+
+fn bad_request(msg: &str) -> KvsError {
+    KvsError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn json_response(status: u16, body: &impl Serialize) -> HttpResponse {
+    HttpResponse {
+        status,
+        // Serializing the plain structures never fails.
+        body: Some(("application/json", serde_json::to_string(body).unwrap())),
+    }
+}
+
+fn empty_response(status: u16) -> HttpResponse {
+    HttpResponse { status, body: None }
+}
+
+pub(crate) fn error_response(status: u16, code: ErrorCode, message: String) -> HttpResponse {
+    json_response(status, &ErrorBody { code, message })
+}
+
+/// Writes a response, which closes the connection.
+pub(crate) fn write_response(writer: &mut impl Write, res: &HttpResponse) -> Result<()> {
+    let reason = match res.status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(writer, "HTTP/1.1 {} {}\r\n", res.status, reason)?;
+    write!(writer, "Connection: close\r\n")?;
+    match &res.body {
+        Some((content_type, body)) => write!(
+            writer,
+            "Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )?,
+        None => write!(writer, "\r\n")?,
+    }
+    Ok(())
+}
@@ -2,14 +2,45 @@
 
 //! A simple key/value store persistent in memory.
 
-pub use client::KvsClient;
-pub use common::{Request, Response};
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use acl::{Acl, Permission};
+pub use address::Address;
+pub use async_client::AsyncKvsClient;
+pub use async_server::{AsyncKvsServer, AsyncShutdownHandle};
+pub use client::{KvsClient, KvsClientBuilder, Watcher};
+pub use client_pool::{KvsClientPool, KvsClientPoolOptions, PooledKvsClient};
+pub use common::{ErrorCode, Request, Response, WatchEvent};
+pub use engine::{
+    Cipher, Compression, Encryption, EngineStats, KvStore, KvStoreOptions, KvStoreStats, KvsEngine,
+    SledKvsEngine,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use http::HttpServer;
+pub use metrics::MetricsServer;
+pub use protocol::{
+    Auth, AuthResponse, Credentials, Handshake, HandshakeResponse, AUTH_CAPABILITY, MAGIC,
+    MAX_FRAME_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use resp::RespServer;
+pub use server::{KvsServer, KvsServerOptions, ShutdownHandle};
+pub use stats::ServerInfo;
+pub use tls::{TlsClientConfig, TlsServerConfig};
+pub use watch::{Subscription, WatchHub, WatchedEngine};
 
+mod acl;
+mod address;
+mod async_client;
+mod async_server;
 mod client;
+mod client_pool;
 mod common;
 mod engine;
 mod error;
+mod http;
+mod metrics;
+mod protocol;
+mod resp;
 mod server;
+mod stats;
+mod stream;
+mod tls;
+mod watch;
@@ -0,0 +1,333 @@
+use std::fmt::Write as _;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::net;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::http::{self, HttpResponse};
+use crate::server::{accept_tcp, DEFAULT_REQUEST_TIMEOUT};
+use crate::stats::{ServerStats, DURATION_BUCKETS};
+use crate::{ErrorCode, KvsEngine, KvsError, Result, ShutdownHandle};
+
+/// The content type of the Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The server of the metrics of a `KvsServer` in the Prometheus text format,
+/// which is created by `KvsServer::metrics_server`.
+///
+/// It serves `GET /metrics`, and closes the connection after each response.
+#[derive(Clone)]
+pub struct MetricsServer<T: KvsEngine> {
+    addr: net::SocketAddr,
+    engine: T,
+    stats: Arc<ServerStats>,
+    request_timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+}
+
+impl<T: KvsEngine + Clone + Send + 'static> MetricsServer<T> {
+    pub(crate) fn new(addr: net::SocketAddr, engine: T, stats: Arc<ServerStats>) -> Self {
+        MetricsServer {
+            addr,
+            engine,
+            stats,
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Sets the timeout of each read of a request and each write of its response,
+    /// or `None` to wait forever.
+    pub fn request_timeout(mut self, request_timeout: Option<Duration>) -> MetricsServer<T> {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the handle to shut down this server, e.g. the one of the `KvsServer`
+    /// sharing the engine.
+    pub fn shutdown(mut self, shutdown: ShutdownHandle) -> MetricsServer<T> {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Creates tcp server to listen on the given addr.
+    ///
+    /// It accepts connections in the main loop, and spawns a thread to serve one request
+    /// of each connection.
+    ///
+    /// It returns after it is shut down by its `ShutdownHandle`,
+    /// and the requests being served are responded.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of binding the address.
+    pub fn start(&mut self) -> Result<()> {
+        let listener = net::TcpListener::bind(self.addr)?;
+        let shutdown = self.shutdown.clone();
+        accept_tcp(listener, &shutdown, |stream| {
+            // The connection is served by a handle of the server with its own engine.
+            let mut server = self.clone();
+            Box::new(move || {
+                if let Err(e) = server.serve(stream) {
+                    warn!("Metrics connection error: {}", e);
+                }
+            })
+        })
+    }
+
+    /// Serves a request of the connection, and then closes it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn serve(&mut self, stream: net::TcpStream) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        stream.set_read_timeout(self.request_timeout)?;
+        stream.set_write_timeout(self.request_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+
+        let res = match http::read_request(&mut reader) {
+            Ok(Some(request)) => {
+                debug!(
+                    "Receive metrics request from {} with {} {:?}",
+                    peer_addr, request.method, request.path
+                );
+                match (request.method.as_str(), request.path.as_slice()) {
+                    ("GET", [path]) if path == "metrics" => match self.render() {
+                        Ok(body) => HttpResponse {
+                            status: 200,
+                            body: Some((CONTENT_TYPE, body)),
+                        },
+                        Err(e) => http::error_response(500, ErrorCode::from(&e), e.to_string()),
+                    },
+                    (method, [path]) if path == "metrics" => http::error_response(
+                        405,
+                        ErrorCode::InvalidRequest,
+                        format!("Method {} is not allowed", method),
+                    ),
+                    _ => http::error_response(
+                        404,
+                        ErrorCode::InvalidRequest,
+                        "No such route".to_owned(),
+                    ),
+                }
+            }
+            Ok(None) => return Ok(()),
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {
+                http::error_response(400, ErrorCode::InvalidRequest, e.to_string())
+            }
+            Err(e) => return Err(e),
+        };
+        http::write_response(&mut writer, &res)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Renders the metrics of the server and the engine.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsEngine::engine_stats`.
+    fn render(&mut self) -> Result<String> {
+        let engine = self.engine.engine_stats()?;
+        let mut out = String::new();
+
+        metric(
+            &mut out,
+            "kvs_uptime_seconds",
+            "gauge",
+            "Time since the server started.",
+        );
+        sample(
+            &mut out,
+            "kvs_uptime_seconds",
+            "",
+            self.stats.uptime().as_secs_f64(),
+        );
+        metric(
+            &mut out,
+            "kvs_connections",
+            "gauge",
+            "Connections being served.",
+        );
+        sample(&mut out, "kvs_connections", "", self.stats.connections());
+        metric(
+            &mut out,
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted, including the rejected ones.",
+        );
+        sample(&mut out, "kvs_connections_total", "", self.stats.accepted());
+        metric(
+            &mut out,
+            "kvs_connections_rejected_total",
+            "counter",
+            "Connections rejected because of too many connections.",
+        );
+        sample(
+            &mut out,
+            "kvs_connections_rejected_total",
+            "",
+            self.stats.rejected(),
+        );
+
+        let requests = self.stats.requests();
+        metric(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests served by their commands.",
+        );
+        for (request, histogram) in &requests {
+            let labels = format!("request=\"{}\"", request);
+            sample(&mut out, "kvs_requests_total", &labels, histogram.count());
+        }
+        metric(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Durations of serving requests by their commands.",
+        );
+        for (request, histogram) in &requests {
+            let buckets = DURATION_BUCKETS.iter().zip(histogram.cumulative_buckets());
+            for (bound, count) in buckets {
+                let labels = format!("request=\"{}\",le=\"{}\"", request, bound);
+                sample(
+                    &mut out,
+                    "kvs_request_duration_seconds_bucket",
+                    &labels,
+                    count,
+                );
+            }
+            let labels = format!("request=\"{}\",le=\"+Inf\"", request);
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_bucket",
+                &labels,
+                histogram.count(),
+            );
+            let labels = format!("request=\"{}\"", request);
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_sum",
+                &labels,
+                histogram.sum().as_secs_f64(),
+            );
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_count",
+                &labels,
+                histogram.count(),
+            );
+        }
+        metric(
+            &mut out,
+            "kvs_request_errors_total",
+            "counter",
+            "Error responses by their error codes.",
+        );
+        for (code, count) in self.stats.errors() {
+            let labels = format!("code=\"{:?}\"", code);
+            sample(&mut out, "kvs_request_errors_total", &labels, count);
+        }
+
+        metric(
+            &mut out,
+            "kvs_engine_info",
+            "gauge",
+            "The storage engine, labeled by its name.",
+        );
+        let labels = format!("engine=\"{}\"", engine.name);
+        sample(&mut out, "kvs_engine_info", &labels, 1);
+        metric(&mut out, "kvs_engine_keys", "gauge", "Live keys.");
+        sample(&mut out, "kvs_engine_keys", "", engine.keys);
+        metric(
+            &mut out,
+            "kvs_engine_disk_bytes",
+            "gauge",
+            "Size of the files in the data directory.",
+        );
+        sample(&mut out, "kvs_engine_disk_bytes", "", engine.disk_size);
+        if let Some(uncompacted_bytes) = engine.uncompacted_bytes {
+            metric(
+                &mut out,
+                "kvs_engine_uncompacted_bytes",
+                "gauge",
+                "Bytes of stale records which compaction reclaims.",
+            );
+            sample(
+                &mut out,
+                "kvs_engine_uncompacted_bytes",
+                "",
+                uncompacted_bytes,
+            );
+            metric(
+                &mut out,
+                "kvs_engine_garbage_ratio",
+                "gauge",
+                "Ratio of the stale bytes to the size of the data directory.",
+            );
+            let ratio = if engine.disk_size == 0 {
+                0.0
+            } else {
+                uncompacted_bytes as f64 / engine.disk_size as f64
+            };
+            sample(&mut out, "kvs_engine_garbage_ratio", "", ratio);
+        }
+        if let Some(log_files) = engine.log_files {
+            metric(&mut out, "kvs_engine_log_files", "gauge", "Log files.");
+            sample(&mut out, "kvs_engine_log_files", "", log_files);
+        }
+        if let Some(compactions) = engine.compactions {
+            metric(
+                &mut out,
+                "kvs_engine_compactions_total",
+                "counter",
+                "Compactions since the engine is opened.",
+            );
+            sample(&mut out, "kvs_engine_compactions_total", "", compactions);
+            if let Some(compaction_duration) = engine.compaction_duration {
+                metric(
+                    &mut out,
+                    "kvs_engine_compaction_duration_seconds",
+                    "summary",
+                    "Durations of compactions since the engine is opened.",
+                );
+                sample(
+                    &mut out,
+                    "kvs_engine_compaction_duration_seconds_sum",
+                    "",
+                    compaction_duration.as_secs_f64(),
+                );
+                sample(
+                    &mut out,
+                    "kvs_engine_compaction_duration_seconds_count",
+                    "",
+                    compactions,
+                );
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Writes the help and type lines of a metric.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    // Writing into a string never fails.
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Writes a sample of a metric with the labels, which may be empty.
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, value).unwrap();
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+    }
+}
@@ -0,0 +1,532 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{Acl, KvsError, Result};
+
+/// The bytes sent by both sides before the handshake, which identify the framed protocol.
+pub const MAGIC: [u8; 4] = *b"KVS\0";
+/// The newest protocol version supported.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The oldest protocol version supported.
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+/// The maximum length in bytes of a frame.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// The capability of authenticating by an `Auth` message after the handshake.
+pub const AUTH_CAPABILITY: &str = "auth";
+/// The capabilities supported by this implementation.
+pub(crate) const CAPABILITIES: &[&str] = &[AUTH_CAPABILITY];
+
+/// The first message of a connection, which the client sends after `MAGIC`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    /// The oldest protocol version the client supports.
+    pub min_version: u16,
+    /// The newest protocol version the client supports.
+    pub max_version: u16,
+    /// The capabilities the client wants to use.
+    pub capabilities: Vec<String>,
+}
+
+/// The message a client sends after the handshake, if the `auth` capability is negotiated,
+/// which a server only does if it has an `Acl`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Auth {
+    /// The credentials of the user, which are required by the server.
+    pub credentials: Option<Credentials>,
+}
+
+/// The reply to an `Auth`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AuthResponse {
+    /// The client is authenticated as the user.
+    Authenticated,
+    /// The connection is closed, because the credentials are missing or wrong.
+    Unauthenticated,
+}
+
+/// The name and the token of a user, which authenticate a client.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Credentials {
+    /// The name of the user.
+    pub user: String,
+    /// The secret token or password of the user.
+    pub token: String,
+}
+
+impl Credentials {
+    /// Creates the credentials of a user.
+    pub fn new(user: String, token: String) -> Credentials {
+        Credentials { user, token }
+    }
+}
+
+// The token is never logged.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The reply to a `Handshake`, which the server sends after `MAGIC`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HandshakeResponse {
+    /// The connection uses the protocol `version`, and the `capabilities` both sides support.
+    Accepted {
+        /// The negotiated protocol version.
+        version: u16,
+        /// The negotiated capabilities.
+        capabilities: Vec<String>,
+    },
+    /// The connection is closed, because no protocol version is supported by both sides.
+    Rejected {
+        /// The reason of rejection.
+        reason: String,
+    },
+    /// The connection is closed, because the server serves too many connections.
+    Busy,
+}
+
+/// The protocol version and capabilities negotiated by a handshake.
+#[derive(Debug, Clone)]
+pub(crate) struct Negotiated {
+    pub(crate) version: u16,
+    pub(crate) capabilities: Vec<String>,
+    /// The name of the user authenticated by the server, if it has an `Acl`.
+    pub(crate) user: Option<String>,
+}
+
+impl Negotiated {
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Writes a message as a frame, which is its bincode encoding prefixed by its big-endian `u32`
+/// length.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode serialization errors.
+pub(crate) fn write_frame<T: Serialize>(mut writer: impl Write, msg: &T) -> Result<()> {
+    writer.write_all(&encode_frame(msg)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a message as a frame asynchronously, like `write_frame`.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode serialization errors.
+pub(crate) async fn write_frame_async<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    msg: &T,
+) -> Result<()> {
+    writer.write_all(&encode_frame(msg)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a message from a frame.
+///
+/// Returns `None`, if the stream ends before the frame.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode deserialization errors.
+pub(crate) fn read_frame<T: DeserializeOwned>(mut reader: impl Read) -> Result<Option<T>> {
+    match read_frame_len(&mut reader)? {
+        Some(len) => Ok(Some(read_frame_payload(reader, len)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the length prefix of a frame.
+///
+/// Returns `None`, if the stream ends before the frame.
+///
+/// # Errors
+///
+/// It propagates I/O errors, or returns an I/O error of `InvalidData` kind
+/// if the length exceeds `MAX_FRAME_LEN`.
+pub(crate) fn read_frame_len(mut reader: impl Read) -> Result<Option<usize>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => Ok(Some(frame_len(len)?)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads a message from the payload of a frame with the given length.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode deserialization errors.
+pub(crate) fn read_frame_payload<T: DeserializeOwned>(reader: impl Read, len: usize) -> Result<T> {
+    // The payload grows as it arrives, rather than by the length the peer claims.
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    check_payload_len(&payload, len)?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+/// Checks that the whole payload of a frame is read.
+///
+/// # Errors
+///
+/// It returns an I/O error of `UnexpectedEof` kind, if the stream ends within the payload.
+fn check_payload_len(payload: &[u8], len: usize) -> Result<()> {
+    if payload.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Reads a message from a frame asynchronously, like `read_frame`.
+///
+/// Returns `None`, if the stream ends before the frame.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode deserialization errors.
+pub(crate) async fn read_frame_async<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<T>> {
+    match read_frame_len_async(reader).await? {
+        Some(len) => Ok(Some(read_frame_payload_async(reader, len).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the length prefix of a frame asynchronously, like `read_frame_len`.
+///
+/// Returns `None`, if the stream ends before the frame.
+///
+/// # Errors
+///
+/// It propagates I/O errors, or returns an I/O error of `InvalidData` kind
+/// if the length exceeds `MAX_FRAME_LEN`.
+pub(crate) async fn read_frame_len_async(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<usize>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => Ok(Some(frame_len(len)?)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads a message from the payload of a frame asynchronously, like `read_frame_payload`.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode deserialization errors.
+pub(crate) async fn read_frame_payload_async<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+    len: usize,
+) -> Result<T> {
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload).await?;
+    check_payload_len(&payload, len)?;
+    Ok(bincode::deserialize(&payload)?)
+}
+
+/// Performs the client side of the handshake, and then authenticates with the credentials
+/// if the server requires them.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors, or returns `KvsError::ProtocolMismatch` error
+/// if the server does not speak the framed protocol or rejects the handshake.
+///
+/// It returns `KvsError::AuthenticationFailed` error if the server rejects the credentials.
+pub(crate) fn connect(
+    mut stream: impl Read + Write,
+    credentials: Option<&Credentials>,
+) -> Result<Negotiated> {
+    stream.write_all(&MAGIC)?;
+    write_frame(&mut stream, &handshake(CAPABILITIES))?;
+
+    let mut magic = [0; 4];
+    check_server_magic(stream.read_exact(&mut magic).map(|()| magic))?;
+    let negotiated = accepted(read_frame(&mut stream)?)?;
+    if !negotiated.has_capability(AUTH_CAPABILITY) {
+        return Ok(negotiated);
+    }
+
+    let auth = Auth {
+        credentials: credentials.cloned(),
+    };
+    write_frame(&mut stream, &auth)?;
+    match read_frame(&mut stream)? {
+        Some(AuthResponse::Authenticated) => Ok(negotiated),
+        Some(AuthResponse::Unauthenticated) => Err(KvsError::AuthenticationFailed),
+        None => Err(KvsError::ProtocolMismatch(
+            "server closed the connection during authentication".to_owned(),
+        )),
+    }
+}
+
+/// Performs the client side of the handshake asynchronously, like `connect`
+/// without credentials, so that it does not offer the `auth` capability.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors, or returns `KvsError::ProtocolMismatch` error
+/// if the server does not speak the framed protocol or rejects the handshake.
+pub(crate) async fn connect_async(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<Negotiated> {
+    writer.write_all(&MAGIC).await?;
+    write_frame_async(writer, &handshake(&[])).await?;
+
+    let mut magic = [0; 4];
+    check_server_magic(reader.read_exact(&mut magic).await.map(|_| magic))?;
+    accepted(read_frame_async(reader).await?)
+}
+
+/// The handshake of this client with the capabilities it wants to use.
+fn handshake(capabilities: &[&str]) -> Handshake {
+    Handshake {
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION,
+        capabilities: capabilities.iter().map(|&c| c.to_owned()).collect(),
+    }
+}
+
+/// Checks the magic bytes read from the server.
+fn check_server_magic(magic: io::Result<[u8; 4]>) -> Result<()> {
+    match magic {
+        Ok(magic) if magic == MAGIC => Ok(()),
+        Ok(_) => Err(KvsError::ProtocolMismatch(
+            "server does not speak the framed protocol".to_owned(),
+        )),
+        // A timeout is reported as it is, since the server may just be slow.
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            Err(e.into())
+        }
+        // So is a TLS alert, e.g. for a client certificate rejected by the server.
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(e.into()),
+        Err(_) => Err(KvsError::ProtocolMismatch(
+            "server closed the connection during handshake".to_owned(),
+        )),
+    }
+}
+
+/// Gets the negotiated protocol from the reply to the handshake.
+fn accepted(res: Option<HandshakeResponse>) -> Result<Negotiated> {
+    match res {
+        Some(HandshakeResponse::Accepted {
+            version,
+            capabilities,
+        }) => Ok(Negotiated {
+            version,
+            capabilities,
+            user: None,
+        }),
+        Some(HandshakeResponse::Rejected { reason }) => Err(KvsError::ProtocolMismatch(reason)),
+        Some(HandshakeResponse::Busy) => Err(KvsError::ServerBusy),
+        None => Err(KvsError::ProtocolMismatch(
+            "server closed the connection during handshake".to_owned(),
+        )),
+    }
+}
+
+/// Performs the server side of the handshake, and then authenticates the client
+/// if the access control list is given.
+///
+/// A server with an access control list requires the `auth` capability,
+/// and rejects clients which do not support it.
+///
+/// Returns `None`, if the client is rejected or does not speak the framed protocol,
+/// and then the connection should be closed.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors.
+pub(crate) fn accept(
+    mut stream: impl Read + Write,
+    acl: Option<&Acl>,
+) -> Result<Option<Negotiated>> {
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+
+    let handshake: Handshake = match read_frame(&mut stream)? {
+        Some(handshake) => handshake,
+        None => return Ok(None),
+    };
+    let (res, negotiated) = negotiate(handshake, acl.is_some());
+    stream.write_all(&MAGIC)?;
+    write_frame(&mut stream, &res)?;
+    let (acl, mut negotiated) = match (acl, negotiated) {
+        (Some(acl), Some(negotiated)) => (acl, negotiated),
+        (_, negotiated) => return Ok(negotiated),
+    };
+
+    let auth: Auth = match read_frame(&mut stream)? {
+        Some(auth) => auth,
+        None => return Ok(None),
+    };
+    match acl.authenticate(auth.credentials.as_ref()) {
+        Some(user) => {
+            write_frame(&mut stream, &AuthResponse::Authenticated)?;
+            negotiated.user = Some(user);
+            Ok(Some(negotiated))
+        }
+        None => {
+            write_frame(&mut stream, &AuthResponse::Unauthenticated)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Performs the server side of the handshake, and rejects the client with
+/// `HandshakeResponse::Busy`.
+///
+/// The handshake of the client is read before the reply, so that closing the connection
+/// does not reset it before the client reads the reply.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors.
+pub(crate) fn reject_busy(mut stream: impl Read + Write) -> Result<()> {
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Ok(());
+    }
+    if read_frame::<Handshake>(&mut stream)?.is_none() {
+        return Ok(());
+    }
+    stream.write_all(&MAGIC)?;
+    write_frame(&mut stream, &HandshakeResponse::Busy)
+}
+
+/// Rejects a client with `HandshakeResponse::Busy` asynchronously, like `reject_busy`.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors.
+pub(crate) async fn reject_busy_async(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).await?;
+    if magic != MAGIC {
+        return Ok(());
+    }
+    if read_frame_async::<Handshake>(reader).await?.is_none() {
+        return Ok(());
+    }
+    writer.write_all(&MAGIC).await?;
+    write_frame_async(writer, &HandshakeResponse::Busy).await
+}
+
+/// Performs the server side of the handshake asynchronously, like `accept`.
+///
+/// Returns `None`, if the client is rejected or does not speak the framed protocol,
+/// and then the connection should be closed.
+///
+/// # Errors
+///
+/// It propagates I/O or bincode errors.
+pub(crate) async fn accept_async(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<Option<Negotiated>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic).await?;
+    if magic != MAGIC {
+        return Ok(None);
+    }
+
+    let handshake: Handshake = match read_frame_async(reader).await? {
+        Some(handshake) => handshake,
+        None => return Ok(None),
+    };
+    let (res, negotiated) = negotiate(handshake, false);
+    writer.write_all(&MAGIC).await?;
+    write_frame_async(writer, &res).await?;
+    Ok(negotiated)
+}
+
+/// Chooses the newest protocol version supported by both sides, and their common capabilities.
+///
+/// The `auth` capability is only used if `auth` is true, in which case it is required.
+fn negotiate(handshake: Handshake, auth: bool) -> (HandshakeResponse, Option<Negotiated>) {
+    let version = handshake.max_version.min(PROTOCOL_VERSION);
+    if version < handshake.min_version.max(MIN_PROTOCOL_VERSION) {
+        let reason = format!(
+            "client supports protocol versions {}-{}, but server supports {}-{}",
+            handshake.min_version, handshake.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        );
+        return (HandshakeResponse::Rejected { reason }, None);
+    }
+
+    let capabilities: Vec<String> = handshake
+        .capabilities
+        .into_iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .filter(|c| auth || c != AUTH_CAPABILITY)
+        .collect();
+    if auth && !capabilities.iter().any(|c| c == AUTH_CAPABILITY) {
+        let reason = "server requires authentication, which client does not support".to_owned();
+        return (HandshakeResponse::Rejected { reason }, None);
+    }
+    let res = HandshakeResponse::Accepted {
+        version,
+        capabilities: capabilities.clone(),
+    };
+    (
+        res,
+        Some(Negotiated {
+            version,
+            capabilities,
+            user: None,
+        }),
+    )
+}
+
+/// Encodes a message as a frame.
+fn encode_frame<T: Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(msg)?;
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(frame_too_large(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Decodes the length prefix of a frame.
+fn frame_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(frame_too_large(len as usize));
+    }
+    Ok(len as usize)
+}
+
+fn frame_too_large(len: usize) -> KvsError {
+    KvsError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds {} bytes", len, MAX_FRAME_LEN),
+    ))
+}
@@ -0,0 +1,418 @@
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+use crate::server::{accept_tcp, DEFAULT_IDLE_TIMEOUT, DEFAULT_REQUEST_TIMEOUT};
+use crate::{ErrorCode, KvsEngine, KvsError, Result, ShutdownHandle, MAX_FRAME_LEN};
+
+/// The maximum number of arguments of a command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// The kvs server speaking the RESP2 protocol of Redis.
+///
+/// It maps `GET`, `SET`, `DEL`, `EXISTS`, `MGET`, `KEYS`, `PING` and `INFO` onto its engine,
+/// so that `redis-cli` and Redis client libraries can talk to it.
+#[derive(Clone)]
+pub struct RespServer<T: KvsEngine> {
+    addr: net::SocketAddr,
+    engine: T,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    shutdown: ShutdownHandle,
+}
+
+/// A reply of the RESP2 protocol.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(usize),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl<T: KvsEngine + Clone + Send + 'static> RespServer<T> {
+    /// Creates a new `RespServer`.
+    pub fn new(addr: net::SocketAddr, engine: T) -> RespServer<T> {
+        RespServer {
+            addr,
+            engine,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Sets the timeout of waiting for the next command or the rest of it,
+    /// after which the connection is closed, or `None` to wait forever.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> RespServer<T> {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the timeout of each write of a reply, or `None` to wait forever.
+    pub fn request_timeout(mut self, request_timeout: Option<Duration>) -> RespServer<T> {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the handle to shut down this server, e.g. the one of the `KvsServer`
+    /// sharing the engine.
+    pub fn shutdown(mut self, shutdown: ShutdownHandle) -> RespServer<T> {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Creates tcp server to listen on the given addr.
+    ///
+    /// It accepts connections in the main loop, and spawns a thread to serve each connection
+    /// until it is closed.
+    ///
+    /// It returns after it is shut down by its `ShutdownHandle`,
+    /// and the requests being served are responded.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of binding the address.
+    pub fn start(&mut self) -> Result<()> {
+        let listener = net::TcpListener::bind(self.addr)?;
+        let shutdown = self.shutdown.clone();
+        accept_tcp(listener, &shutdown, |stream| {
+            // The connection is served by a handle of the server with its own engine.
+            let mut server = self.clone();
+            Box::new(move || {
+                if let Err(e) = server.serve(stream) {
+                    warn!("RESP connection error: {}", e);
+                }
+            })
+        })
+    }
+
+    /// Serves a connection.
+    ///
+    /// It reads commands from the tcp stream one by one, and writes the reply of each command.
+    /// The connection is closed after replying a protocol error, as Redis does.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors.
+    fn serve(&mut self, stream: net::TcpStream) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        stream.set_read_timeout(self.idle_timeout)?;
+        stream.set_write_timeout(self.request_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+
+        // The read side of the stream is shut down as well, but buffered commands are not served.
+        while !self.shutdown.is_shutdown() {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    let reply = Reply::Error(format!("ERR Protocol error: {}", e));
+                    write_reply(&mut writer, &reply)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if args.is_empty() {
+                continue;
+            }
+            debug!("Receive RESP command from {} with {:?}", peer_addr, args);
+
+            let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+            let reply = match String::from_utf8(args[0].clone()) {
+                Ok(name) => self.execute(&name.to_ascii_uppercase(), &args[1..]),
+                Err(_) => Reply::Error("ERR unknown command".to_owned()),
+            };
+            write_reply(&mut writer, &reply)?;
+            writer.flush()?;
+            if quit {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a command with its upper case name and arguments.
+    fn execute(&mut self, name: &str, args: &[Vec<u8>]) -> Reply {
+        let args: Vec<String> = match args
+            .iter()
+            .map(|arg| String::from_utf8(arg.clone()))
+            .collect()
+        {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR keys and values must be valid UTF-8".to_owned()),
+        };
+        let arity_ok = match name {
+            "PING" => args.len() <= 1,
+            "GET" => args.len() == 1,
+            "SET" => args.len() == 2,
+            "DEL" | "EXISTS" | "MGET" => !args.is_empty(),
+            "KEYS" => args.len() == 1,
+            "INFO" => args.len() <= 1,
+            "SELECT" => args.len() == 1,
+            "QUIT" => true,
+            _ => return Reply::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        };
+        if !arity_ok {
+            return Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            ));
+        }
+
+        match self.execute_checked(name, args) {
+            Ok(reply) => reply,
+            Err(e) => error_reply(&e),
+        }
+    }
+
+    /// Executes a command whose number of arguments is checked.
+    fn execute_checked(&mut self, name: &str, mut args: Vec<String>) -> Result<Reply> {
+        let reply = match name {
+            "PING" => match args.pop() {
+                Some(msg) => Reply::Bulk(Some(msg)),
+                None => Reply::Simple("PONG"),
+            },
+            "GET" => Reply::Bulk(self.engine.get(args.remove(0))?),
+            "SET" => {
+                let value = args.pop().unwrap();
+                self.engine.set(args.pop().unwrap(), value)?;
+                Reply::Simple("OK")
+            }
+            "DEL" => {
+                let mut removed = 0;
+                for key in args {
+                    match self.engine.remove(key) {
+                        Ok(()) => removed += 1,
+                        Err(KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Reply::Integer(removed)
+            }
+            "EXISTS" => {
+                let mut exists = 0;
+                for key in args {
+                    if self.engine.get(key)?.is_some() {
+                        exists += 1;
+                    }
+                }
+                Reply::Integer(exists)
+            }
+            "MGET" => Reply::Array(
+                args.into_iter()
+                    .map(|key| Ok(Reply::Bulk(self.engine.get(key)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            "KEYS" => {
+                let pattern: Vec<char> = args[0].chars().collect();
+                Reply::Array(
+                    self.engine
+                        .keys()?
+                        .into_iter()
+                        .filter(|key| glob_match(&pattern, &key.chars().collect::<Vec<_>>()))
+                        .map(|key| Reply::Bulk(Some(key)))
+                        .collect(),
+                )
+            }
+            "INFO" => Reply::Bulk(Some(format!(
+                "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\n\r\n\
+                 # Keyspace\r\ndb0:keys={}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                self.engine.keys()?.len()
+            ))),
+            // Only the database 0 exists, which some client libraries select on connect.
+            "SELECT" if args[0] == "0" => Reply::Simple("OK"),
+            "SELECT" => Reply::Error("ERR DB index is out of range".to_owned()),
+            "QUIT" => Reply::Simple("OK"),
+            _ => unreachable!(),
+        };
+        Ok(reply)
+    }
+}
+
+/// Maps an engine error into an error reply.
+fn error_reply(err: &KvsError) -> Reply {
+    match ErrorCode::from(err) {
+        ErrorCode::ReadOnly => {
+            Reply::Error("READONLY You can't write against a read only server.".to_owned())
+        }
+        _ => Reply::Error(format!("ERR {}", err)),
+    }
+}
+
+/// Reads a command, which is either an array of bulk strings or an inline command.
+///
+/// Returns `None`, if the stream ends before the command.
+///
+/// # Errors
+///
+/// It propagates I/O errors, or returns an I/O error of `InvalidData` kind
+/// if the command is malformed.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let line = String::from_utf8_lossy(&line);
+        return Ok(Some(
+            line.split_whitespace()
+                .map(|arg| arg.as_bytes().to_vec())
+                .collect(),
+        ));
+    }
+
+    // Buffers grow as the arguments arrive, rather than by the lengths the client claims.
+    let count = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_len(&line[1..], MAX_FRAME_LEN as usize)?;
+
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its trailing CRLF.
+///
+/// Returns `None`, if the stream ends before the line.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // Bound the line, so that a client without newlines can not exhaust the memory.
+    reader
+        .take(u64::from(MAX_FRAME_LEN))
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line is not terminated by LF"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parses a length no more than `max`.
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Writes a reply in the RESP2 encoding.
+fn write_reply(writer: &mut impl Write, reply: &Reply) -> Result<()> {
+    match reply {
+        Reply::Simple(msg) => write!(writer, "+{}\r\n", msg)?,
+        // An error message is a single line.
+        Reply::Error(msg) => write!(writer, "-{}\r\n", msg.replace(&['\r', '\n'][..], " "))?,
+        Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+        Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s)?,
+        Reply::Bulk(None) => write!(writer, "$-1\r\n")?,
+        Reply::Array(replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Matches a string against a glob-style pattern of `KEYS`,
+/// which supports `*`, `?`, `[...]`, `[^...]`, ranges in brackets and `\` escapes.
+///
+/// It only backtracks to the last `*`, which takes O(n·m) time in the worst case.
+fn glob_match(pattern: &[char], s: &[char]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The pattern position after the last `*`, and the string position it is retried at.
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, i));
+        } else if let Some(next) = match_char(pattern, p, s[i]) {
+            p = next;
+            i += 1;
+        } else if let Some((star_p, star_i)) = star {
+            // Let the last `*` consume one more character.
+            p = star_p;
+            i = star_i + 1;
+            star = Some((star_p, i));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches a character against the pattern element at `p`, which is not `*`.
+///
+/// Returns the position of the next element, or `None` if it does not match.
+fn match_char(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern.get(p)? {
+        '?' => Some(p + 1),
+        '[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != ']' {
+                if pattern[i] == '\\' && i + 1 < pattern.len() {
+                    i += 1;
+                    matched |= pattern[i] == c;
+                    i += 1;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+                    let (lo, hi) = if pattern[i] <= pattern[i + 2] {
+                        (pattern[i], pattern[i + 2])
+                    } else {
+                        (pattern[i + 2], pattern[i])
+                    };
+                    matched |= lo <= c && c <= hi;
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // An unterminated bracket ends the pattern.
+            let next = if i < pattern.len() { i + 1 } else { i };
+            if matched != negate {
+                Some(next)
+            } else {
+                None
+            }
+        }
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then(|| p + 2),
+        &expected => (expected == c).then(|| p + 1),
+    }
+}
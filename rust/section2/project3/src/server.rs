@@ -1,65 +1,664 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
 use std::net;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{debug, warn};
+use log::{debug, info, warn};
 
-use crate::{KvsEngine, Request, Response, Result};
+use crate::metrics::MetricsServer;
+use crate::protocol;
+use crate::stats::ServerStats;
+use crate::stream::{BufStream, Listener, Socket, Stream};
+use crate::{
+    Acl, Address, ErrorCode, KvsEngine, KvsError, Request, Response, Result, TlsServerConfig,
+    WatchHub, WatchedEngine, MAX_FRAME_LEN,
+};
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The interval of checking whether a watching client is gone or the server is shut down.
+pub(crate) const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// The maximum number of excess connections being rejected at the same time,
+/// beyond which they are closed without a reply.
+const MAX_REJECTING: usize = 16;
+/// The timeout of each read and write of rejecting an excess connection.
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Options to create a `KvsServer`.
+#[derive(Debug, Clone)]
+pub struct KvsServerOptions {
+    pub(crate) max_connections: usize,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) max_request_size: u32,
+    pub(crate) tls: Option<TlsServerConfig>,
+    pub(crate) acl: Option<Acl>,
+}
+
+impl KvsServerOptions {
+    /// Creates options with default values.
+    pub fn new() -> KvsServerOptions {
+        KvsServerOptions {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            max_request_size: MAX_FRAME_LEN,
+            tls: None,
+            acl: None,
+        }
+    }
+
+    /// Sets the maximum number of connections served at the same time.
+    ///
+    /// Excess connections are rejected with `HandshakeResponse::Busy`,
+    /// which `KvsClient` returns as `KvsError::ServerBusy` error.
+    /// Only a few of them are rejected at a time, and the others are closed without a reply.
+    pub fn max_connections(mut self, max_connections: usize) -> KvsServerOptions {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets the duration after which a connection without requests is closed.
+    ///
+    /// `None` keeps idle connections open.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> KvsServerOptions {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the timeout of each read and write of the handshake, of the rest of a request
+    /// once its first bytes are read, and of its response.
+    ///
+    /// The connection is closed once it times out. `None` blocks forever.
+    pub fn request_timeout(mut self, request_timeout: Option<Duration>) -> KvsServerOptions {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a request frame, no more than `MAX_FRAME_LEN`.
+    ///
+    /// A larger request is answered with `ErrorCode::RequestTooLarge`,
+    /// and then the connection is closed.
+    pub fn max_request_size(mut self, max_request_size: u32) -> KvsServerOptions {
+        self.max_request_size = max_request_size.min(MAX_FRAME_LEN);
+        self
+    }
+
+    /// Sets the TLS configuration, so that every connection is served over TLS.
+    ///
+    /// `None` serves plain tcp.
+    pub fn tls(mut self, tls: Option<TlsServerConfig>) -> KvsServerOptions {
+        self.tls = tls;
+        self
+    }
+
+    /// Sets the access control list, so that every client must authenticate in the handshake,
+    /// and every request must be permitted to its user.
+    ///
+    /// A request which is not permitted is answered with `ErrorCode::PermissionDenied`.
+    /// `None` permits every request from anyone.
+    pub fn acl(mut self, acl: Option<Acl>) -> KvsServerOptions {
+        self.acl = acl;
+        self
+    }
+}
+
+impl Default for KvsServerOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// The kvs server.
-pub struct KvsServer<T: KvsEngine> {
-    addr: net::SocketAddr,
-    engine: T,
+///
+/// Each connection is served by a thread with a handle of the engine.
+///
+/// Every write through the engine is published to the clients watching its key,
+/// including the writes through the handle returned by `engine`.
+pub struct KvsServer<T: KvsEngine + Clone + Send + 'static> {
+    addr: Address,
+    engine: WatchedEngine<T>,
+    options: KvsServerOptions,
+    shutdown: ShutdownHandle,
+    stats: Arc<ServerStats>,
+    rejecting: Arc<AtomicUsize>,
+}
+
+/// A handle to shut down a `KvsServer` from another thread, e.g. a signal handler.
+///
+/// The same handle can be given to the `RespServer`, `HttpServer` and `MetricsServer`
+/// sharing the engine, so that they are shut down together.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownState>,
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    is_shutdown: AtomicBool,
+    /// The addresses the servers listen on, which are connected to wake up their accept loops.
+    local_addrs: Mutex<Vec<Address>>,
+    next_id: AtomicU64,
+    /// The sockets of the connections being served, by their ids.
+    connections: Mutex<HashMap<u64, Socket>>,
+}
+
+/// Unregisters a connection from its `ShutdownHandle` on drop.
+pub(crate) struct Registration {
+    id: u64,
+    shutdown: ShutdownHandle,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.shutdown
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
 }
 
-impl<T: KvsEngine> KvsServer<T> {
-    /// Creates a new `KvsServer`.
-    pub fn new(addr: net::SocketAddr, engine: T) -> KvsServer<T> {
-        KvsServer { addr, engine }
+impl ShutdownHandle {
+    /// Shuts down the servers.
+    ///
+    /// The servers stop accepting connections, and stop reading requests from their connections.
+    /// `KvsServer::start` returns after the requests being served are responded,
+    /// and the engine is flushed. The `start` of the other servers returns after
+    /// the requests being served are responded.
+    pub fn shutdown(&self) {
+        {
+            // The flag is set with the lock, so that no connection is registered afterwards.
+            let connections = self.inner.connections.lock().unwrap();
+            self.inner.is_shutdown.store(true, Ordering::SeqCst);
+            for socket in connections.values() {
+                let _ = socket.shutdown(net::Shutdown::Read);
+            }
+        }
+
+        for mut addr in self.inner.local_addrs.lock().unwrap().clone() {
+            if let Address::Tcp(addr) = &mut addr {
+                if addr.ip().is_unspecified() {
+                    let localhost = match addr {
+                        net::SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+                        net::SocketAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+                    };
+                    addr.set_ip(localhost);
+                }
+            }
+            // The accept loop checks the flag after accepting this connection.
+            let _ = Socket::connect(&addr, None);
+        }
+    }
+
+    /// Returns whether the server is shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.is_shutdown.load(Ordering::SeqCst)
     }
 
-    /// Creates tcp server to listen on the given addr.
+    /// Adds the address of a listener, which is connected by shutdown to wake up its accept loop.
     ///
-    /// It accepts connections in the main loop.
-    /// After accepting connection, it read bytes from tcp stream and deserialize it into `Command`.
-    /// Then use its engine to deal with this command and responses data or errors from engine.
+    /// Returns `false` if the server is shut down.
+    pub(crate) fn listen(&self, addr: Address) -> bool {
+        let mut local_addrs = self.inner.local_addrs.lock().unwrap();
+        if self.is_shutdown() {
+            return false;
+        }
+        local_addrs.push(addr);
+        true
+    }
+
+    /// Registers a handle of the socket of a connection, so that shutdown stops reading from it
+    /// until the returned registration is dropped.
+    ///
+    /// Returns `None` if the server is shut down.
+    pub(crate) fn register(&self, socket: Socket) -> Option<Registration> {
+        let mut connections = self.inner.connections.lock().unwrap();
+        if self.is_shutdown() {
+            return None;
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        connections.insert(id, socket);
+        Some(Registration {
+            id,
+            shutdown: self.clone(),
+        })
+    }
+}
+
+/// Decrements the number of excess connections being rejected on drop.
+pub(crate) struct RejectGuard {
+    rejecting: Arc<AtomicUsize>,
+}
+
+impl RejectGuard {
+    /// Increments the number of excess connections being rejected.
+    ///
+    /// Returns `None` if there are `MAX_REJECTING` of them already,
+    /// and then the connection should be closed without a reply.
+    pub(crate) fn new(rejecting: &Arc<AtomicUsize>) -> Option<RejectGuard> {
+        if rejecting.fetch_add(1, Ordering::SeqCst) >= MAX_REJECTING {
+            rejecting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(RejectGuard {
+            rejecting: rejecting.clone(),
+        })
+    }
+}
+
+impl Drop for RejectGuard {
+    fn drop(&mut self) {
+        self.rejecting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Decrements the number of connections on drop.
+struct ConnectionGuard {
+    stats: Arc<ServerStats>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.disconnect();
+    }
+}
+
+impl<T: KvsEngine + Clone + Send + 'static> KvsServer<T> {
+    /// Creates a new `KvsServer` with default options.
+    ///
+    /// The address is a tcp socket address or a Unix domain socket.
+    pub fn new(addr: impl Into<Address>, engine: T) -> KvsServer<T> {
+        Self::with_options(addr, engine, KvsServerOptions::new())
+    }
+
+    /// Creates a new `KvsServer` with the given options.
+    pub fn with_options(
+        addr: impl Into<Address>,
+        engine: T,
+        options: KvsServerOptions,
+    ) -> KvsServer<T> {
+        KvsServer {
+            addr: addr.into(),
+            engine: WatchedEngine::new(engine, WatchHub::new()),
+            options,
+            shutdown: ShutdownHandle::default(),
+            stats: Arc::default(),
+            rejecting: Arc::default(),
+        }
+    }
+
+    /// Returns a handle to shut down this server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns a handle of the engine, whose writes are published to the watchers of this server.
+    ///
+    /// Other servers sharing the engine, e.g. `RespServer`, should write through this handle,
+    /// so that their writes are watched as well.
+    pub fn engine(&self) -> WatchedEngine<T> {
+        self.engine.clone()
+    }
+
+    /// Creates a server of the metrics of this server and its engine in the Prometheus text format,
+    /// which listens on the given addr once it is started.
+    pub fn metrics_server(&self, addr: net::SocketAddr) -> MetricsServer<WatchedEngine<T>> {
+        MetricsServer::new(addr, self.engine(), self.stats.clone())
+    }
+
+    /// Creates tcp server, or Unix domain socket server, to listen on the given addr.
+    ///
+    /// The socket file of a Unix domain socket is removed when the server returns.
+    ///
+    /// It accepts connections in the main loop, and spawns a thread to serve each connection
+    /// until it is closed, or to reject it if there are too many connections.
+    ///
+    /// It returns after it is shut down by a `ShutdownHandle`, the requests being served are
+    /// responded, and the engine is flushed.
     ///
     /// # Errors
     ///
-    /// It propagates I/O, or bincode serialization and deserialization errors.
+    /// It propagates I/O errors of binding the address, or errors of flushing the engine.
     pub fn start(&mut self) -> Result<()> {
-        let listener = net::TcpListener::bind(self.addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    let command = bincode::deserialize_from(&mut stream);
-                    debug!("Receive from {} with {:?}", stream.peer_addr()?, command);
-
-                    if let Err(e) = command {
-                        let res = Response::new_error(e);
-                        bincode::serialize_into(&mut stream, &res)?;
+        let listener = Listener::bind(&self.addr)?;
+        if !self.shutdown.listen(listener.local_addr()?) {
+            return Ok(());
+        }
+        self.stats.start();
+        let mut workers: Vec<thread::JoinHandle<()>> = Vec::new();
+        loop {
+            let socket = listener.accept();
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Accept error: {}", e);
+                    continue;
+                }
+            };
+
+            let options = self.options.clone();
+            if self.stats.connect() >= options.max_connections {
+                self.stats.reject();
+                let guard = match RejectGuard::new(&self.rejecting) {
+                    Some(guard) => guard,
+                    None => {
+                        debug!("Close excess connection: too many connections being rejected");
                         continue;
                     }
+                };
+                thread::spawn(move || {
+                    let _guard = guard;
+                    if let Err(e) = reject(socket, &options) {
+                        debug!("Connection error while rejecting: {}", e);
+                    }
+                });
+                continue;
+            }
 
-                    let res = match command.unwrap() {
-                        Request::Set { key, value } => match self.engine.set(key, value) {
-                            Ok(()) => Response::new_success(None),
-                            Err(e) => Response::new_error(e),
-                        },
-                        Request::Remove { key } => match self.engine.remove(key) {
-                            Ok(()) => Response::new_success(None),
-                            Err(e) => Response::new_error(e),
-                        },
-                        Request::Get { key } => match self.engine.get(key) {
-                            Ok(value) => Response::new_success(value),
-                            Err(e) => Response::new_error(e),
-                        },
-                    };
-                    bincode::serialize_into(&mut stream, &res)?;
-                }
+            let guard = ConnectionGuard {
+                stats: self.stats.clone(),
+            };
+            let registration = match socket.try_clone() {
+                Ok(handle) => match self.shutdown.register(handle) {
+                    Some(registration) => registration,
+                    None => break,
+                },
                 Err(e) => {
-                    warn!("Tcp accept error: {}", e);
+                    warn!("Connection error: {}", e);
+                    continue;
                 }
-            }
+            };
+            let engine = self.engine.clone();
+            let shutdown = self.shutdown.clone();
+            let stats = self.stats.clone();
+            workers.retain(|worker| !worker.is_finished());
+            workers.push(thread::spawn(move || {
+                let _guard = guard;
+                let _registration = registration;
+                if let Err(e) = serve(engine, socket, &options, &shutdown, &stats) {
+                    warn!("Connection error: {}", e);
+                }
+            }));
         }
+
+        drop(listener);
+        for worker in workers {
+            let _ = worker.join();
+        }
+        self.engine.flush()?;
+        info!("Server is shut down");
         Ok(())
     }
 }
+
+/// Accepts tcp connections until the handle is shut down, and spawns a thread to run
+/// the task returned by `serve` for each connection.
+///
+/// It returns after shutdown, once the tasks of the connections being served are finished.
+///
+/// # Errors
+///
+/// It propagates I/O errors of the listener.
+pub(crate) fn accept_tcp(
+    listener: net::TcpListener,
+    shutdown: &ShutdownHandle,
+    mut serve: impl FnMut(net::TcpStream) -> Box<dyn FnOnce() + Send>,
+) -> Result<()> {
+    if !shutdown.listen(Address::Tcp(listener.local_addr()?)) {
+        return Ok(());
+    }
+    let mut workers: Vec<thread::JoinHandle<()>> = Vec::new();
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Tcp accept error: {}", e);
+                continue;
+            }
+        };
+        let registration = match stream.try_clone() {
+            Ok(handle) => match shutdown.register(Socket::Tcp(handle)) {
+                Some(registration) => registration,
+                None => break,
+            },
+            Err(e) => {
+                warn!("Connection error: {}", e);
+                continue;
+            }
+        };
+        let task = serve(stream);
+        workers.retain(|worker| !worker.is_finished());
+        workers.push(thread::spawn(move || {
+            let _registration = registration;
+            task();
+        }));
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Rejects a connection, because there are too many connections.
+///
+/// The short `REJECT_TIMEOUT` applies instead of the request timeout,
+/// so that excess connections do not hold their threads for long.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization and deserialization errors.
+fn reject(socket: Socket, options: &KvsServerOptions) -> Result<()> {
+    warn!("Reject connection from {}: server busy", socket.peer()?);
+    socket.set_read_timeout(Some(REJECT_TIMEOUT))?;
+    socket.set_write_timeout(Some(REJECT_TIMEOUT))?;
+    let stream = Stream::accept(socket, options.tls.as_ref())?;
+    protocol::reject_busy(BufStream::new(stream))
+}
+
+/// Serves a connection.
+///
+/// After the handshake, it reads framed `Request`s from the socket one by one.
+/// Then use its engine to deal with each request and responses data or errors from engine.
+///
+/// A `Request::Watch` turns the rest of the connection into a stream of `WatchEvent`s.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization and deserialization errors.
+fn serve<E: KvsEngine>(
+    mut engine: WatchedEngine<E>,
+    socket: Socket,
+    options: &KvsServerOptions,
+    shutdown: &ShutdownHandle,
+    stats: &ServerStats,
+) -> Result<()> {
+    let peer_addr = socket.peer()?;
+    socket.set_read_timeout(options.request_timeout)?;
+    socket.set_write_timeout(options.request_timeout)?;
+    let mut stream = BufStream::new(Stream::accept(socket, options.tls.as_ref())?);
+
+    let negotiated = match protocol::accept(&mut stream, options.acl.as_ref())? {
+        Some(negotiated) => negotiated,
+        None => {
+            warn!("Reject connection from {}", peer_addr);
+            return Ok(());
+        }
+    };
+    debug!(
+        "Accept connection from {} with protocol version {} and capabilities {:?}",
+        peer_addr, negotiated.version, negotiated.capabilities
+    );
+    if let Some(user) = &negotiated.user {
+        debug!(
+            "Authenticate connection from {} as user {}",
+            peer_addr, user
+        );
+    }
+
+    // The read side of the stream is shut down as well, but buffered requests are not served.
+    while !shutdown.is_shutdown() {
+        // The idle timeout applies while waiting for the next request.
+        stream.socket().set_read_timeout(options.idle_timeout)?;
+        let len = match protocol::read_frame_len(&mut stream) {
+            Ok(Some(len)) => len,
+            Ok(None) => return Ok(()),
+            Err(KvsError::Io(ref e)) if is_timeout(e) => {
+                debug!("Close idle connection from {}", peer_addr);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if len > options.max_request_size as usize {
+            let res = Response::Err {
+                code: ErrorCode::RequestTooLarge,
+                message: format!(
+                    "request of {} bytes exceeds {} bytes",
+                    len, options.max_request_size
+                ),
+            };
+            stats.record_response(&res);
+            protocol::write_frame(&mut stream, &res)?;
+            warn!("Close connection from {}: request too large", peer_addr);
+            // Drain the request before closing, so that the response is not lost by a reset.
+            stream.socket().shutdown(net::Shutdown::Write)?;
+            stream.socket().set_read_timeout(options.request_timeout)?;
+            io::copy(&mut (&mut stream).take(len as u64), &mut io::sink())?;
+            return Ok(());
+        }
+
+        stream.socket().set_read_timeout(options.request_timeout)?;
+        let request = match protocol::read_frame_payload::<Request>(&mut stream, len) {
+            Ok(request) => request,
+            // The frame is skipped as a whole, so the following ones can still be read.
+            Err(e @ KvsError::Bincode(_)) => {
+                let res = Response::new_error(&e);
+                stats.record_response(&res);
+                protocol::write_frame(&mut stream, &res)?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Receive from {} with {:?}", peer_addr, request);
+
+        let started = Instant::now();
+        let authorized = match (&options.acl, &negotiated.user) {
+            (Some(acl), Some(user)) => acl.authorize(user, &request),
+            _ => Ok(()),
+        };
+        let name = request.name();
+        let res = match (authorized, request) {
+            (Ok(()), Request::Watch { key, prefix }) => {
+                stats.record_request(name, started.elapsed(), &Response::new_success(None));
+                return watch(&mut stream, engine.hub(), key, prefix, &peer_addr, shutdown);
+            }
+            (Ok(()), Request::Info) => match stats.info(&mut engine) {
+                Ok(info) => Response::Info(info),
+                Err(e) => Response::new_error(&e),
+            },
+            (Ok(()), request) => handle_request(&mut engine, request),
+            (Err(e), _) => {
+                warn!("Deny request from {}: {}", peer_addr, e);
+                Response::new_error(&e)
+            }
+        };
+        stats.record_request(name, started.elapsed(), &res);
+        protocol::write_frame(&mut stream, &res)?;
+    }
+    Ok(())
+}
+
+/// Sends the changes of the key, or of every key starting with it, to a client
+/// until it closes the connection or sends anything else.
+///
+/// The connection is closed if the client lags too far behind, or if the server is shut down.
+///
+/// # Errors
+///
+/// It propagates I/O, or bincode serialization errors.
+fn watch(
+    stream: &mut BufStream,
+    hub: &WatchHub,
+    key: String,
+    prefix: bool,
+    peer_addr: &str,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    debug!(
+        "Watch {} {} for {}",
+        if prefix { "prefix" } else { "key" },
+        key,
+        peer_addr
+    );
+    let events = hub.subscribe(key, prefix);
+    protocol::write_frame(&mut *stream, &Response::new_success(None))?;
+
+    while !shutdown.is_shutdown() {
+        match events.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => protocol::write_frame(&mut *stream, &event)?,
+            Err(RecvTimeoutError::Timeout) => {
+                if !stream.read_buffer().is_empty() || is_readable(stream.socket())? {
+                    debug!("Stop watching for {}", peer_addr);
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                warn!("Close connection from {}: watcher lags behind", peer_addr);
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks without blocking whether the socket has data to read or is closed by the peer.
+fn is_readable(socket: &Socket) -> Result<bool> {
+    socket.set_nonblocking(true)?;
+    let readable = match socket.probe() {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    };
+    socket.set_nonblocking(false)?;
+    readable
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// Uses the engine to deal with a request, and responses data or errors from engine.
+pub(crate) fn handle_request(engine: &mut impl KvsEngine, request: Request) -> Response {
+    match request {
+        Request::Set { key, value } => match engine.set(key, value) {
+            Ok(()) => Response::new_success(None),
+            Err(e) => Response::new_error(&e),
+        },
+        Request::Remove { key } => match engine.remove(key) {
+            Ok(()) => Response::new_success(None),
+            Err(e) => Response::new_error(&e),
+        },
+        Request::Get { key } => match engine.get(key) {
+            Ok(value) => Response::new_success(value),
+            Err(e) => Response::new_error(&e),
+        },
+        // The servers streaming watch events and counting statistics handle them before.
+        Request::Watch { .. } | Request::Info => Response::Err {
+            code: ErrorCode::InvalidRequest,
+            message: format!("{} is not supported by this server", request.name()),
+        },
+    }
+}
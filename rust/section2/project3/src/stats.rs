@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{EngineStats, ErrorCode, KvsEngine, Response, Result};
+
+/// The upper bounds in seconds of the buckets of request durations,
+/// besides the last bucket without a bound.
+pub(crate) const DURATION_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// Statistics of a `KvsServer`, which `KvsClient::info` gets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The time since the server started.
+    pub uptime: Duration,
+    /// The statistics of the engine.
+    pub engine: EngineStats,
+    /// The number of connections being served.
+    pub connections: u64,
+    /// The number of requests served, by their names, e.g. "set".
+    pub requests: BTreeMap<String, u64>,
+    /// The number of error responses, by their error codes.
+    pub errors: BTreeMap<ErrorCode, u64>,
+}
+
+/// The counters of a `KvsServer`, which are shared by its connections.
+#[derive(Default)]
+pub(crate) struct ServerStats {
+    started: Mutex<Option<Instant>>,
+    connections: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    /// The durations of requests, whose counts are the numbers of requests, by their names.
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<ErrorCode, u64>>,
+}
+
+/// The distribution of request durations over `DURATION_BUCKETS`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Histogram {
+    /// The number of durations in each bucket, where the last one has no upper bound.
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    sum: Duration,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += duration;
+        self.count += 1;
+    }
+
+    /// Returns the number of durations no longer than each bound of `DURATION_BUCKETS`.
+    pub(crate) fn cumulative_buckets(&self) -> Vec<u64> {
+        self.buckets[..DURATION_BUCKETS.len()]
+            .iter()
+            .scan(0, |acc, &count| {
+                *acc += count;
+                Some(*acc)
+            })
+            .collect()
+    }
+
+    pub(crate) fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl ServerStats {
+    /// Starts counting the uptime.
+    pub(crate) fn start(&self) {
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Returns the time since the server started.
+    pub(crate) fn uptime(&self) -> Duration {
+        match *self.started.lock().unwrap() {
+            Some(started) => started.elapsed(),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Counts an accepted connection, and returns the number of connections before it.
+    pub(crate) fn connect(&self) -> usize {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        self.connections.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Uncounts a connection which is closed.
+    pub(crate) fn disconnect(&self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Uncounts a connection which is rejected, because there are too many connections.
+    pub(crate) fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::SeqCst);
+        self.disconnect();
+    }
+
+    /// Returns the number of connections being served.
+    pub(crate) fn connections(&self) -> u64 {
+        self.connections.load(Ordering::SeqCst) as u64
+    }
+
+    /// Returns the number of connections accepted since the server started,
+    /// including the rejected ones.
+    pub(crate) fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of connections rejected since the server started.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    /// Counts a request by its name with the duration of serving it, and its response.
+    pub(crate) fn record_request(
+        &self,
+        request: &'static str,
+        duration: Duration,
+        response: &Response,
+    ) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(request)
+            .or_default()
+            .observe(duration);
+        self.record_response(response);
+    }
+
+    /// Counts a response if it is an error, including those to requests which can not be decoded.
+    pub(crate) fn record_response(&self, response: &Response) {
+        if let Response::Err { code, .. } = response {
+            *self.errors.lock().unwrap().entry(*code).or_insert(0) += 1;
+        }
+    }
+
+    /// Returns the durations of requests by their names.
+    pub(crate) fn requests(&self) -> BTreeMap<&'static str, Histogram> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Returns the number of error responses by their error codes.
+    pub(crate) fn errors(&self) -> BTreeMap<ErrorCode, u64> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    /// Collects the statistics of the server with those of the engine.
+    ///
+    /// # Errors
+    ///
+    /// It propagates errors of `KvsEngine::engine_stats`.
+    pub(crate) fn info(&self, engine: &mut impl KvsEngine) -> Result<ServerInfo> {
+        Ok(ServerInfo {
+            uptime: self.uptime(),
+            engine: engine.engine_stats()?,
+            connections: self.connections(),
+            requests: self
+                .requests()
+                .into_iter()
+                .map(|(request, histogram)| (request.to_owned(), histogram.count()))
+                .collect(),
+            errors: self.errors(),
+        })
+    }
+}
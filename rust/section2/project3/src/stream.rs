@@ -0,0 +1,340 @@
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net;
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rustls::{ClientConnection, ConnectionCommon, ServerConnection, SideData, StreamOwned};
+
+use crate::tls::tls_error;
+use crate::{Address, Result, TlsClientConfig, TlsServerConfig};
+
+/// A connected socket, which is either tcp or a Unix domain socket.
+pub(crate) enum Socket {
+    Tcp(net::TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Connects to the address, within the timeout if it is given.
+    ///
+    /// The timeout does not apply to a Unix domain socket, which connects at once.
+    pub(crate) fn connect(addr: &Address, timeout: Option<Duration>) -> io::Result<Socket> {
+        match addr {
+            Address::Tcp(addr) => match timeout {
+                Some(timeout) => net::TcpStream::connect_timeout(addr, timeout),
+                None => net::TcpStream::connect(addr),
+            }
+            .map(Socket::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(Socket::Unix),
+        }
+    }
+
+    /// Describes the peer in logs.
+    ///
+    /// The peer of a Unix domain socket has no name, so it is described by the socket path.
+    pub(crate) fn peer(&self) -> io::Result<String> {
+        match self {
+            Socket::Tcp(stream) => Ok(stream.peer_addr()?.to_string()),
+            #[cfg(unix)]
+            Socket::Unix(stream) => Ok(match stream.local_addr()?.as_pathname() {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix socket".to_owned(),
+            }),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Reads a byte if there is one, which is 0 bytes at the end of the stream.
+    ///
+    /// A tcp socket peeks the byte, but a Unix domain socket can not, so it consumes the byte,
+    /// after which the connection is out of sync.
+    pub(crate) fn probe(&self) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.peek(&mut [0; 1]),
+            #[cfg(unix)]
+            Socket::Unix(stream) => (&*stream).read(&mut [0; 1]),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A listening socket, which is either tcp or a Unix domain socket.
+pub(crate) enum Listener {
+    Tcp(net::TcpListener),
+    /// The socket file is removed on drop.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Listens on the address.
+    ///
+    /// The socket file of a Unix domain socket left behind by a server which is gone
+    /// is replaced, but that of a server still listening is not.
+    pub(crate) fn bind(addr: &Address) -> io::Result<Listener> {
+        match addr {
+            Address::Tcp(addr) => net::TcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                if path.exists() {
+                    match UnixStream::connect(path) {
+                        Ok(_) => return Err(io::Error::from(io::ErrorKind::AddrInUse)),
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                            fs::remove_file(path)?
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                let listener = UnixListener::bind(path)?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Returns the address listened on, with the port assigned if port 0 is bound.
+    pub(crate) fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Socket::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Socket::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = self {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// The stream of a connection, which is either plain or TLS over a socket.
+pub(crate) enum Stream {
+    Plain(Socket),
+    TlsClient(Box<StreamOwned<ClientConnection, Socket>>),
+    TlsServer(Box<StreamOwned<ServerConnection, Socket>>),
+}
+
+impl Stream {
+    /// Starts the client side of a connection to the address, with TLS if it is configured.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `KvsError::Tls` error if the TLS handshake fails.
+    pub(crate) fn connect(
+        socket: Socket,
+        addr: &Address,
+        tls: Option<&TlsClientConfig>,
+    ) -> Result<Stream> {
+        match tls {
+            Some(tls) => {
+                let mut stream = StreamOwned::new(tls.connect(addr)?, socket);
+                handshake(&mut stream)?;
+                Ok(Stream::TlsClient(Box::new(stream)))
+            }
+            None => Ok(Stream::Plain(socket)),
+        }
+    }
+
+    /// Starts the server side of a connection, with TLS if it is configured.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors, or returns `KvsError::Tls` error if the TLS handshake fails.
+    pub(crate) fn accept(socket: Socket, tls: Option<&TlsServerConfig>) -> Result<Stream> {
+        match tls {
+            Some(tls) => {
+                let mut stream = StreamOwned::new(tls.accept()?, socket);
+                handshake(&mut stream)?;
+                Ok(Stream::TlsServer(Box::new(stream)))
+            }
+            None => Ok(Stream::Plain(socket)),
+        }
+    }
+
+    /// Returns the underlying socket.
+    pub(crate) fn socket(&self) -> &Socket {
+        match self {
+            Stream::Plain(socket) => socket,
+            Stream::TlsClient(stream) => &stream.sock,
+            Stream::TlsServer(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::TlsClient(stream) => stream.read(buf),
+            Stream::TlsServer(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::TlsClient(stream) => stream.write(buf),
+            Stream::TlsServer(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::TlsClient(stream) => stream.flush(),
+            Stream::TlsServer(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Completes the TLS handshake, so that its failure is reported before any request.
+fn handshake<C, S>(stream: &mut StreamOwned<C, Socket>) -> Result<()>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    while stream.conn.is_handshaking() {
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .map_err(|e| tls_error(e.into()))?;
+    }
+    Ok(())
+}
+
+/// A `Stream` with buffered reads, and writes buffered until it is flushed,
+/// so that a message and its frame are sent at once.
+pub(crate) struct BufStream {
+    reader: BufReader<Stream>,
+    write_buf: Vec<u8>,
+}
+
+impl BufStream {
+    pub(crate) fn new(stream: Stream) -> BufStream {
+        BufStream {
+            reader: BufReader::new(stream),
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Returns the underlying socket.
+    pub(crate) fn socket(&self) -> &Socket {
+        self.reader.get_ref().socket()
+    }
+
+    /// Returns the data read but not consumed yet.
+    pub(crate) fn read_buffer(&self) -> &[u8] {
+        self.reader.buffer()
+    }
+}
+
+impl Read for BufStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for BufStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.write_all(&self.write_buf)?;
+        self.write_buf.clear();
+        stream.flush()
+    }
+}
@@ -0,0 +1,183 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+
+use crate::{Address, KvsError, Result};
+
+/// The TLS configuration of `KvsServer`.
+#[derive(Debug, Clone)]
+pub struct TlsServerConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServerConfig {
+    /// Loads the certificate chain and the private key of the server from PEM files.
+    ///
+    /// If the file of client CA certificates is given, every client must authenticate with
+    /// a certificate issued by one of them, i.e. mutual TLS.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` error if a file can not be read or parsed,
+    /// or the key does not match the certificate.
+    pub fn from_pem_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: Option<&Path>,
+    ) -> Result<TlsServerConfig> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(root_store(client_ca)?, provider())
+                        .build()
+                        .map_err(|e| KvsError::Tls(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config =
+            builder.with_single_cert(load_certs(cert.as_ref())?, load_key(key.as_ref())?)?;
+        Ok(TlsServerConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Starts the server side of a TLS connection.
+    pub(crate) fn accept(&self) -> Result<ServerConnection> {
+        Ok(ServerConnection::new(self.config.clone())?)
+    }
+}
+
+/// The TLS configuration of `KvsClient`.
+#[derive(Debug, Clone)]
+pub struct TlsClientConfig {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsClientConfig {
+    /// Loads the CA certificates trusted to issue the certificate of the server from a PEM file.
+    ///
+    /// If the files of the certificate chain and the private key of the client are given,
+    /// the client authenticates with them when the server asks for it, i.e. mutual TLS.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` error if a file can not be read or parsed,
+    /// or the key does not match the certificate.
+    pub fn from_pem_files(
+        ca: impl AsRef<Path>,
+        client_cert_and_key: Option<(&Path, &Path)>,
+    ) -> Result<TlsClientConfig> {
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(root_store(ca.as_ref())?, provider())
+                .build()
+                .map_err(|e| KvsError::Tls(e.to_string()))?;
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_webpki_verifier(verifier);
+        let config = match client_cert_and_key {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsClientConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Sets the name which the certificate of the server must be valid for,
+    /// which is either a DNS name or an IP address.
+    ///
+    /// By default, it is the IP address connected to, and it is required for
+    /// a Unix domain socket.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` error if the name is invalid.
+    pub fn server_name(mut self, server_name: &str) -> Result<TlsClientConfig> {
+        let server_name = ServerName::try_from(server_name.to_owned())
+            .map_err(|e| KvsError::Tls(format!("{}: {}", e, server_name)))?;
+        self.server_name = Some(server_name);
+        Ok(self)
+    }
+
+    /// Starts the client side of a TLS connection to the address.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Tls` error if the server name is not set for
+    /// a Unix domain socket.
+    pub(crate) fn connect(&self, addr: &Address) -> Result<ClientConnection> {
+        let server_name = match (&self.server_name, addr) {
+            (Some(server_name), _) => server_name.clone(),
+            (None, Address::Tcp(addr)) => ServerName::from(addr.ip()),
+            #[cfg(unix)]
+            (None, Address::Unix(_)) => {
+                return Err(KvsError::Tls(
+                    "server name is required for a Unix domain socket".to_owned(),
+                ))
+            }
+        };
+        Ok(ClientConnection::new(self.config.clone(), server_name)?)
+    }
+}
+
+/// Maps I/O errors of TLS failures, e.g. an untrusted certificate, into `KvsError::Tls`.
+pub(crate) fn tls_error(err: KvsError) -> KvsError {
+    match err {
+        KvsError::Io(e) => match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+            Some(tls) => KvsError::Tls(tls.to_string()),
+            None => KvsError::Io(e),
+        },
+        e => e,
+    }
+}
+
+/// The cryptography of both sides, which does not depend on the process-wide default.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Loads the certificates in a PEM file.
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key in a PEM file.
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+/// Loads the CA certificates in a PEM file as trust anchors.
+fn root_store(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> KvsError {
+    KvsError::Tls(format!("{}: {}", path.display(), err))
+}
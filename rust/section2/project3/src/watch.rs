@@ -0,0 +1,173 @@
+use std::ops::Deref;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use crate::{EngineStats, KvsEngine, Result, WatchEvent};
+
+/// The number of events buffered for a watcher, beyond which it is disconnected as lagging.
+const WATCHER_CAPACITY: usize = 1024;
+
+/// The notification hub of key changes, which dispatches every published event
+/// to the watchers of its key.
+///
+/// A hub can be cloned into handles which share the watchers.
+#[derive(Clone, Default)]
+pub struct WatchHub {
+    subscribers: Arc<Mutex<Subscribers>>,
+    /// Held by `WatchedEngine` through a write and its publishing, and by `subscribe`,
+    /// so that events are published in the order they are written in.
+    writes: Arc<Mutex<()>>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    list: Vec<Subscriber>,
+}
+
+struct Subscriber {
+    id: u64,
+    key: String,
+    prefix: bool,
+    sender: SyncSender<WatchEvent>,
+}
+
+impl WatchHub {
+    /// Creates a hub without watchers.
+    pub fn new() -> WatchHub {
+        WatchHub::default()
+    }
+
+    /// Watches the changes of the key, or of every key starting with it if `prefix` is true.
+    ///
+    /// The watcher is removed once the subscription is dropped, or once it lags behind
+    /// by too many events, after which its receiver is disconnected.
+    pub fn subscribe(&self, key: String, prefix: bool) -> Subscription {
+        let (sender, receiver) = mpsc::sync_channel(WATCHER_CAPACITY);
+        let _writes = self.writes.lock().unwrap();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.list.push(Subscriber {
+            id,
+            key,
+            prefix,
+            sender,
+        });
+        Subscription {
+            id,
+            receiver,
+            subscribers: Arc::clone(&self.subscribers),
+        }
+    }
+
+    /// Sends the event to the watchers of its key.
+    pub fn publish(&self, event: WatchEvent) {
+        let key = event.key();
+        self.subscribers.lock().unwrap().list.retain(|subscriber| {
+            let matched = if subscriber.prefix {
+                key.starts_with(&subscriber.key)
+            } else {
+                key == subscriber.key
+            };
+            if !matched {
+                return true;
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Returns the number of watchers.
+    pub fn watchers(&self) -> usize {
+        self.subscribers.lock().unwrap().list.len()
+    }
+}
+
+/// The receiver of the events of a watcher, which removes the watcher from its hub on drop.
+pub struct Subscription {
+    id: u64,
+    receiver: Receiver<WatchEvent>,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl Deref for Subscription {
+    type Target = Receiver<WatchEvent>;
+
+    fn deref(&self) -> &Receiver<WatchEvent> {
+        &self.receiver
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.subscribers
+            .lock()
+            .unwrap()
+            .list
+            .retain(|subscriber| subscriber.id != id);
+    }
+}
+
+/// An engine which publishes its successful writes to a `WatchHub`.
+///
+/// Writes through handles sharing a hub are serialized, so that their events are published
+/// in the order they are written in. Nothing is cloned for publishing while there is no watcher.
+#[derive(Clone)]
+pub struct WatchedEngine<E: KvsEngine> {
+    engine: E,
+    hub: WatchHub,
+}
+
+impl<E: KvsEngine> WatchedEngine<E> {
+    /// Wraps the engine to publish its writes to the hub.
+    pub fn new(engine: E, hub: WatchHub) -> WatchedEngine<E> {
+        WatchedEngine { engine, hub }
+    }
+
+    /// Returns the hub the writes are published to.
+    pub fn hub(&self) -> &WatchHub {
+        &self.hub
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for WatchedEngine<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let _writes = self.hub.writes.lock().unwrap();
+        if self.hub.watchers() == 0 {
+            return self.engine.set(key, value);
+        }
+        self.engine.set(key.clone(), value.clone())?;
+        self.hub.publish(WatchEvent::Set { key, value });
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let _writes = self.hub.writes.lock().unwrap();
+        if self.hub.watchers() == 0 {
+            return self.engine.remove(key);
+        }
+        self.engine.remove(key.clone())?;
+        self.hub.publish(WatchEvent::Remove { key });
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.engine.keys()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn engine_stats(&mut self) -> Result<EngineStats> {
+        self.engine.engine_stats()
+    }
+}
@@ -1,4 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::thread;
+
+use kvs::{Cipher, Compression, Encryption, KvStore, KvStoreOptions, KvsEngine, KvsError, Result};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +129,624 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should read values from all log files with only one file kept open.
+#[test]
+fn bounded_open_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_open_files(1);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for iter in 0..300 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value299".to_owned())
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value299".to_owned())
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should rotate the active log file by size and read values from all of them.
+#[test]
+fn log_file_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_threshold(None);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_file_count(temp_dir.path()) > 10);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should only merge the oldest log files holding enough stale data.
+#[test]
+fn partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(1024);
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), options.clone().compaction_threshold(None))?;
+
+    // Stale data in the oldest files.
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("hot0".to_owned())?;
+    // Live data in the newer files.
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let total_files = log_file_count(temp_dir.path());
+
+    drop(store);
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        options.compaction_threshold(Some(4 * 1024)),
+    )?;
+    store.set("hot1".to_owned(), "100".to_owned())?;
+    let compacted_files = log_file_count(temp_dir.path());
+    assert!(compacted_files < total_files);
+    // The files of live data are not merged.
+    assert!(compacted_files > 10);
+
+    assert_eq!(store.get("hot0".to_owned())?, None);
+    assert_eq!(store.get("hot1".to_owned())?, Some("100".to_owned()));
+    for key_id in 2..10 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some("99".to_owned()));
+    }
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot0".to_owned())?, None);
+    assert_eq!(store.get("hot1".to_owned())?, Some("100".to_owned()));
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should read values from cloned handles in other threads during compaction.
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_threshold(Some(4 * 1024));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let mut store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..1000 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    // Trigger compactions while reading.
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should serve hot values from the value cache and never return a stale one.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+
+    drop(store);
+    let options = KvStoreOptions::new().cache_capacity(0);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats().cache_hits, 0);
+
+    Ok(())
+}
+
+// Should count live keys, log files and stale bytes of the data directory.
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.engine_stats()?;
+    assert_eq!(stats.name, "kvs");
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.log_files, Some(1));
+    assert_eq!(stats.uncompacted_bytes, Some(0));
+    assert!(stats.disk_size > 0);
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.engine_stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.uncompacted_bytes.unwrap() > 0);
+
+    drop(store);
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    let stats = store.engine_stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.uncompacted_bytes, None);
+    assert_eq!(stats.compactions, None);
+    drop(store);
+
+    let options = KvStoreOptions::new().compaction_threshold(Some(1024));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.engine_stats()?.compactions, Some(0));
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.engine_stats()?;
+    assert!(stats.compactions.unwrap() > 0);
+    assert!(stats.compaction_duration.is_some());
+    assert!(stats.uncompacted_bytes.unwrap() < 1024);
+
+    Ok(())
+}
+
+// Should compress values in the log, and read them after compaction with another algorithm.
+#[test]
+fn value_compression() -> Result<()> {
+    let value = |key_id: u32| format!("{{\"id\": {}, \"name\": \"{}\"}}", key_id, "a".repeat(1000));
+    let dir_size = |path: &Path| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(plain_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+
+    for &(compression, recompression) in &[
+        (Compression::Lz4, Compression::Zstd),
+        (Compression::Zstd, Compression::None),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new()
+            .compression(compression)
+            .compaction_threshold(Some(1024));
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value(key_id))?;
+        }
+        assert!(dir_size(temp_dir.path()) * 4 < dir_size(plain_dir.path()));
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+        }
+
+        // Open from disk again with another compression, and compact the old values.
+        drop(store);
+        let options = options.compression(recompression);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), value(key_id))?;
+        }
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// Should read encrypted values after reopening and compaction, and fail with a wrong key
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    let key = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+    let wrong_key = "ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+    let encryption = Encryption::from_hex(Cipher::Aes256Gcm, key)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .encryption(Some(encryption))
+        .compaction_threshold(Some(1024));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "secret-value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    // Neither keys nor values are written in plaintext.
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let content = String::from_utf8_lossy(&fs::read(entry.path())?).into_owned();
+            assert!(!content.contains("secret-value"));
+            assert!(!content.contains("key1"));
+        }
+    }
+
+    // Open from disk again with another cipher, and compact the old values.
+    drop(store);
+    let encryption = Encryption::from_hex(Cipher::ChaCha20Poly1305, key)?;
+    let options = options.encryption(Some(encryption));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 50..100 {
+        store.set(format!("key{}", key_id), "secret-value".to_owned())?;
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("secret-value".to_owned())
+        );
+    }
+
+    // Opening with a wrong key or without a key fails.
+    drop(store);
+    let encryption = Encryption::from_hex(Cipher::Aes256Gcm, wrong_key)?;
+    let options = KvStoreOptions::new().encryption(Some(encryption));
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvsError::Decryption) => {}
+        _ => panic!("opening with a wrong key should fail"),
+    }
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(_)) => {}
+        _ => panic!("opening without a key should fail"),
+    }
+
+    match Encryption::from_hex(Cipher::Aes256Gcm, "0011") {
+        Err(KvsError::InvalidEncryptionKey) => {}
+        _ => panic!("a short key should be invalid"),
+    }
+
+    Ok(())
+}
+
+// Should store large values in blob files, and collect stale blob files
+#[test]
+fn blob_storage() -> Result<()> {
+    let value = |key_id: u32, version: u32| format!("{}-{}-{}", key_id, version, "v".repeat(4000));
+    let file_size = |path: &Path, extension: &str| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some(OsStr::new(extension)))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(Some(1024))
+        .max_file_size(64 * 1024)
+        .compaction_threshold(Some(16 * 1024));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for version in 0..20 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), value(key_id, version))?;
+        }
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    // Values are not in log files, and stale values are collected.
+    assert!(file_size(temp_dir.path(), "log") < 20 * 1024);
+    let blob_size = file_size(temp_dir.path(), "blob");
+    assert!(blob_size >= 20 * 4000);
+    assert!(blob_size < 20 * 4000 * 4);
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(value(key_id, 19))
+        );
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(value(key_id, 19))
+        );
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    for key_id in 0..20 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert!(file_size(temp_dir.path(), "blob") < 64 * 1024 * 2);
+
+    Ok(())
+}
+
+// Should compress the values moved out of collected blob files with the current algorithm
+#[test]
+fn blob_recompression() -> Result<()> {
+    let value = |key_id: u32| format!("{}-{}", key_id, "v".repeat(4000));
+    let blob_size = |path: &Path| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some(OsStr::new("blob")))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compression(Compression::None)
+        .blob_threshold(Some(64))
+        .max_file_size(16 * 1024)
+        .compaction_threshold(Some(1024 * 1024));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    assert!(blob_size(temp_dir.path()) >= 20 * 4000);
+
+    // Open from disk again with compression, and make the old blob files mostly stale.
+    drop(store);
+    let options = options.compression(Compression::Zstd);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in (0..20).filter(|key_id| key_id % 4 != 0) {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    assert!(blob_size(temp_dir.path()) < 4000);
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+
+    Ok(())
+}
+
+// Should refuse to open a data directory of another engine or format version
+#[test]
+fn manifest_compatibility() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest = fs::read_to_string(&manifest_path)?;
+    assert!(manifest.contains("format_version = 2"));
+    assert!(manifest.contains("engine = kvs"));
+
+    fs::write(
+        &manifest_path,
+        manifest.replace("engine = kvs", "engine = sled"),
+    )?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::WrongEngineType) => {}
+        _ => panic!("opening a sled directory should fail"),
+    }
+
+    fs::write(
+        &manifest_path,
+        manifest.replace("format_version = 2", "format_version = 3"),
+    )?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(_)) => {}
+        _ => panic!("opening a newer format version should fail"),
+    }
+
+    // A data directory without manifest is opened by its log files.
+    fs::remove_file(&manifest_path)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(manifest_path.exists());
+
+    Ok(())
+}
+
+// Should upgrade a data directory from the JSON format
+#[test]
+fn upgrade_json_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Remove":{"key":"key1"}}{"Set":{"key":"key3","value":"value3"}}"#,
+    )?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(_)) => {}
+        _ => panic!("opening a JSON directory should fail"),
+    }
+
+    KvStore::upgrade(temp_dir.path(), KvStoreOptions::new())?;
+    // Upgrading the current format does nothing.
+    KvStore::upgrade(temp_dir.path(), KvStoreOptions::new())?;
+    assert!(temp_dir.path().join("json-backup").join("0.log").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should lock the data directory until all the handles are dropped
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = store.clone();
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked) => {}
+        _ => panic!("opening a locked directory should fail"),
+    }
+
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked) => {}
+        _ => panic!("the lock should be held by the remaining handle"),
+    }
+
+    drop(handle);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Should read a store in read-only mode without modifying its data directory
+#[test]
+fn read_only_mode() -> Result<()> {
+    let dir_content = |path: &Path| -> Vec<(String, u64)> {
+        let mut content: Vec<(String, u64)> = WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .map(|entry| {
+                let name = entry.path().display().to_string();
+                (name, entry.metadata().unwrap().len())
+            })
+            .collect();
+        content.sort();
+        content
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let content = dir_content(temp_dir.path());
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    let mut other_store = KvStore::open_read_only(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked) => {}
+        _ => panic!("opening a directory in read-only mode for writing should fail"),
+    }
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            other_store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    match store.set("key1".to_owned(), "value".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("set should fail in read-only mode"),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        _ => panic!("remove should fail in read-only mode"),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    drop(other_store);
+    assert_eq!(dir_content(temp_dir.path()), content);
+
+    // A missing `LOCK` file is not created, and the directory itself is locked instead.
+    fs::remove_file(temp_dir.path().join("LOCK"))?;
+    let content = dir_content(temp_dir.path());
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(dir_content(temp_dir.path()), content);
+    if cfg!(unix) {
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::Locked) => {}
+            _ => {
+                panic!("opening a directory without LOCK in read-only mode for writing should fail")
+            }
+        }
+    }
+    drop(store);
+
+    Ok(())
+}
+
+fn log_file_count(path: &Path) -> usize {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|res| res.ok())
+        .filter(|entry| entry.path().extension() == Some(OsStr::new("log")))
+        .count()
+}
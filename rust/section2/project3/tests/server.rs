@@ -0,0 +1,1047 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::{
+    Acl, Address, AsyncKvsClient, AsyncKvsServer, Credentials, ErrorCode, Handshake,
+    HandshakeResponse, HttpServer, KvStore, KvsClient, KvsClientBuilder, KvsClientPool,
+    KvsClientPoolOptions, KvsEngine, KvsError, KvsServer, KvsServerOptions, Request, RespServer,
+    Result, TlsClientConfig, TlsServerConfig, WatchEvent, MAGIC, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use tempfile::TempDir;
+
+// Should serve several requests on one connection after the handshake
+#[test]
+fn client_requests() -> Result<()> {
+    let addr = "127.0.0.1:4100".parse().unwrap();
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::new(addr)?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should serve async clients while holding many idle connections, until shutdown
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let addr = "127.0.0.1:4107".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = AsyncKvsServer::new(addr, engine);
+    let mut engine = server.engine();
+    let shutdown = server.shutdown_handle();
+    let handle = tokio::spawn(async move { server.start().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let watcher = tokio::task::spawn_blocking(move || {
+        KvsClient::new(addr).and_then(|client| client.watch("key".to_owned(), true))
+    })
+    .await
+    .unwrap()?;
+
+    let mut idle_clients = Vec::new();
+    for _ in 0..200 {
+        idle_clients.push(AsyncKvsClient::new(addr).await?);
+    }
+
+    let mut client = AsyncKvsClient::new(addr).await?;
+    assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).await?;
+    match client.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // The blocking client speaks the same protocol.
+    let mut sync_client = tokio::task::spawn_blocking(move || KvsClient::new(addr))
+        .await
+        .unwrap()?;
+    tokio::task::spawn_blocking(move || sync_client.set("key2".to_owned(), "value2".to_owned()))
+        .await
+        .unwrap()?;
+    assert_eq!(
+        idle_clients[0].get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+
+    // Writes through the handle of the engine are watched along with those of the clients.
+    tokio::task::spawn_blocking(move || engine.remove("key2".to_owned()))
+        .await
+        .unwrap()?;
+    let events = tokio::task::spawn_blocking(move || watcher.take(4).collect::<Result<Vec<_>>>())
+        .await
+        .unwrap()?;
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            WatchEvent::Remove {
+                key: "key1".to_owned(),
+            },
+            WatchEvent::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            WatchEvent::Remove {
+                key: "key2".to_owned(),
+            },
+        ]
+    );
+
+    // Shutdown closes the idle connections, and returns from `start` with the engine flushed.
+    client.set("key3".to_owned(), "value3".to_owned()).await?;
+    assert!(!shutdown.is_shutdown());
+    shutdown.shutdown();
+    assert!(shutdown.is_shutdown());
+    handle.await.unwrap()?;
+    assert!(idle_clients[1].get("key3".to_owned()).await.is_err());
+    assert!(AsyncKvsClient::new(addr).await.is_err());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should reuse connections of a pool shared by threads
+#[test]
+fn client_pool() -> Result<()> {
+    let addr = "127.0.0.1:4108".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    // The blocking server serves one connection at a time, so each thread checks out
+    // its own connection from the async one.
+    thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(AsyncKvsServer::new(addr, engine).start())
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let options = KvsClientPoolOptions::new()
+        .min_connections(1)
+        .max_connections(2);
+    let pool = KvsClientPool::with_options(addr, options)?;
+    assert_eq!(pool.open_connections(), 1);
+    assert_eq!(pool.idle_connections(), 1);
+
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(pool.open_connections(), 1);
+
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let pool = pool.clone();
+            thread::spawn(move || -> Result<()> {
+                for j in 0..20 {
+                    let key = format!("key{}-{}", i, j);
+                    pool.set(key.clone(), j.to_string())?;
+                    assert_eq!(pool.get(key)?, Some(j.to_string()));
+                    assert!(pool.open_connections() <= 2);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert!(pool.open_connections() <= 2);
+
+    {
+        let mut first = pool.get_client()?;
+        let mut second = pool.get_client()?;
+        assert_eq!(pool.idle_connections(), 0);
+        first.remove("key1".to_owned())?;
+        match second.remove("key1".to_owned()) {
+            Err(KvsError::KeyNotFound) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+    assert_eq!(pool.idle_connections(), 2);
+
+    // Idle connections are closed down to the minimum.
+    let options = KvsClientPoolOptions::new().idle_timeout(Some(Duration::from_millis(0)));
+    let pool = KvsClientPool::with_options(addr, options)?;
+    assert_eq!(pool.get("key1".to_owned())?, None);
+    assert_eq!(pool.open_connections(), 0);
+
+    Ok(())
+}
+
+// Should time out on a hung server, and retry connecting with backoff
+#[test]
+fn client_timeouts_and_retries() -> Result<()> {
+    // The server accepts connections, but never responds.
+    let addr: SocketAddr = "127.0.0.1:4109".parse().unwrap();
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
+    });
+
+    let start = Instant::now();
+    let res = KvsClientBuilder::new(addr)
+        .read_timeout(Some(Duration::from_millis(100)))
+        .max_retries(1)
+        .initial_backoff(Duration::from_millis(50))
+        .build();
+    match res {
+        Err(KvsError::Timeout) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(250));
+
+    // Nothing listens on the address.
+    let addr: SocketAddr = "127.0.0.1:4110".parse().unwrap();
+    let start = Instant::now();
+    let res = KvsClientBuilder::new(addr)
+        .connect_timeout(Some(Duration::from_millis(100)))
+        .max_retries(2)
+        .initial_backoff(Duration::from_millis(50))
+        .build();
+    assert!(res.is_err());
+    assert!(start.elapsed() >= Duration::from_millis(150));
+
+    Ok(())
+}
+
+// Should reject excess connections, and close idle connections and oversized requests
+#[test]
+fn server_limits() -> Result<()> {
+    check_server_limits("127.0.0.1:4111", start_server_with_options)
+}
+
+// Should enforce the same limits in the async server
+#[test]
+fn async_server_limits() -> Result<()> {
+    check_server_limits("127.0.0.1:4120", start_async_server_with_options)
+}
+
+fn check_server_limits(
+    addr: &str,
+    start: fn(SocketAddr, KvsServerOptions) -> Result<TempDir>,
+) -> Result<()> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let options = KvsServerOptions::new()
+        .max_connections(1)
+        .idle_timeout(Some(Duration::from_millis(300)))
+        .request_timeout(Some(Duration::from_millis(300)))
+        .max_request_size(64);
+    let _temp_dir = start(addr, options)?;
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    match KvsClient::new(addr) {
+        Err(KvsError::ServerBusy) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+
+    match client.set("key1".to_owned(), "v".repeat(100)) {
+        Err(KvsError::RemoteError(ErrorCode::RequestTooLarge, _)) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+    // The client reconnects after the connection is closed.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The idle connection is closed, and then another client is accepted.
+    thread::sleep(Duration::from_millis(500));
+    let mut other = KvsClient::new(addr)?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(other);
+
+    // A half request is closed after the request timeout.
+    thread::sleep(Duration::from_millis(100));
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&MAGIC)?;
+    write_frame(
+        &mut stream,
+        &Handshake {
+            min_version: PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        },
+    )?;
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    read_frame(&mut stream)?;
+    stream.write_all(&[0, 0, 0, 16, 0])?;
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // Only a few excess connections are rejected at a time, and the others are closed at once.
+    thread::sleep(Duration::from_millis(100));
+    let streams = (0..21)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    thread::sleep(Duration::from_millis(100));
+    for mut stream in streams.iter().skip(17) {
+        stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+    }
+    let mut stream = &streams[1];
+    stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+    assert!(stream.read(&mut [0; 1]).is_err());
+
+    Ok(())
+}
+
+// Should stop serving and return from `start` after shutdown, along with the servers sharing it
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4112".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let resp_addr: SocketAddr = "127.0.0.1:4122".parse().unwrap();
+    let mut server = KvsServer::new(addr, engine);
+    let shutdown = server.shutdown_handle();
+    // The RESP server sharing the engine is shut down along with the server.
+    let mut resp_server = RespServer::new(resp_addr, server.engine()).shutdown(shutdown.clone());
+    let handle = thread::spawn(move || server.start());
+    let resp_handle = thread::spawn(move || resp_server.start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::new(addr)?;
+    let mut idle_client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut resp_client = TcpStream::connect(resp_addr)?;
+
+    assert!(!shutdown.is_shutdown());
+    shutdown.shutdown();
+    assert!(shutdown.is_shutdown());
+    handle.join().unwrap()?;
+    resp_handle.join().unwrap()?;
+
+    assert!(idle_client.get("key1".to_owned()).is_err());
+    assert!(KvsClient::new(addr).is_err());
+    let mut buf = Vec::new();
+    resp_client.read_to_end(&mut buf)?;
+    assert!(buf.is_empty());
+    assert!(TcpStream::connect(resp_addr).is_err());
+
+    // The data directory is unlocked and flushed.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should stream the changes of watched keys from every write path until shutdown
+#[test]
+fn watch_notifications() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4116".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let mut server = KvsServer::new(addr, engine);
+    let mut engine = server.engine();
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut prefix_watcher = KvsClient::new(addr)?.watch("config/".to_owned(), true)?;
+    let mut key_watcher = KvsClient::new(addr)?.watch("config/a".to_owned(), false)?;
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("config/a".to_owned(), "1".to_owned())?;
+    client.set("other".to_owned(), "2".to_owned())?;
+    client.set("config/b".to_owned(), "3".to_owned())?;
+    // Writes through the handle of the engine, e.g. from RESP or HTTP, are watched as well.
+    engine.remove("config/a".to_owned())?;
+
+    let set_a = WatchEvent::Set {
+        key: "config/a".to_owned(),
+        value: "1".to_owned(),
+    };
+    let set_b = WatchEvent::Set {
+        key: "config/b".to_owned(),
+        value: "3".to_owned(),
+    };
+    let remove_a = WatchEvent::Remove {
+        key: "config/a".to_owned(),
+    };
+    assert_eq!(prefix_watcher.next().unwrap()?, set_a);
+    assert_eq!(prefix_watcher.next().unwrap()?, set_b);
+    assert_eq!(prefix_watcher.next().unwrap()?, remove_a);
+    assert_eq!(key_watcher.next().unwrap()?, set_a);
+    assert_eq!(key_watcher.next().unwrap()?, remove_a);
+
+    // A watcher of a key which is never written is removed once its client is gone.
+    let unused_watcher = KvsClient::new(addr)?.watch("unused".to_owned(), false)?;
+    assert_eq!(engine.hub().watchers(), 3);
+    drop(unused_watcher);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.hub().watchers(), 2);
+
+    // Watchers are closed by shutdown.
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(prefix_watcher.next().is_none());
+    assert!(key_watcher.next().is_none());
+    assert_eq!(engine.hub().watchers(), 0);
+
+    Ok(())
+}
+
+// Should count requests, errors and connections, along with the statistics of the engine
+#[test]
+fn server_info() -> Result<()> {
+    check_server_info("127.0.0.1:4117", start_server_with_options)
+}
+
+// Should count the same statistics in the async server
+#[test]
+fn async_server_info() -> Result<()> {
+    check_server_info("127.0.0.1:4121", start_async_server_with_options)
+}
+
+fn check_server_info(
+    addr: &str,
+    start: fn(SocketAddr, KvsServerOptions) -> Result<TempDir>,
+) -> Result<()> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let _temp_dir = start(addr, KvsServerOptions::new())?;
+
+    let mut client = KvsClient::new(addr)?;
+    let _idle_client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.remove("key3".to_owned()).is_err());
+
+    let info = client.info()?;
+    assert!(info.uptime > Duration::from_secs(0));
+    assert_eq!(info.connections, 2);
+    assert_eq!(info.engine.name, "kvs");
+    assert_eq!(info.engine.keys, 2);
+    assert_eq!(info.engine.log_files, Some(1));
+    assert!(info.engine.disk_size > 0);
+    assert_eq!(info.requests.get("set"), Some(&2));
+    assert_eq!(info.requests.get("get"), Some(&1));
+    assert_eq!(info.requests.get("remove"), Some(&1));
+    assert_eq!(info.errors.get(&ErrorCode::KeyNotFound), Some(&1));
+
+    // The previous info request is counted as well.
+    assert_eq!(client.info()?.requests.get("info"), Some(&1));
+
+    Ok(())
+}
+
+// Should serve the metrics of the server and the engine in the Prometheus text format
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4118".parse().unwrap();
+    let metrics_addr: SocketAddr = "127.0.0.1:4119".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(addr, KvStore::open(temp_dir.path())?);
+    let mut metrics_server = server.metrics_server(metrics_addr);
+    thread::spawn(move || server.start());
+    thread::spawn(move || metrics_server.start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let scrape = |target: &str| -> Result<String> {
+        let mut stream = TcpStream::connect(metrics_addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target)?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        Ok(res)
+    };
+    let res = scrape("/metrics")?;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    for line in &[
+        "kvs_connections 1",
+        "kvs_connections_total 1",
+        "kvs_requests_total{request=\"set\"} 2",
+        "kvs_requests_total{request=\"remove\"} 1",
+        "kvs_request_duration_seconds_bucket{request=\"set\",le=\"+Inf\"} 2",
+        "kvs_request_duration_seconds_count{request=\"set\"} 2",
+        "kvs_request_errors_total{code=\"KeyNotFound\"} 1",
+        "kvs_engine_info{engine=\"kvs\"} 1",
+        "kvs_engine_keys 1",
+        "kvs_engine_log_files 1",
+        "kvs_engine_compactions_total 0",
+        "kvs_engine_compaction_duration_seconds_sum 0",
+        "kvs_engine_compaction_duration_seconds_count 0",
+    ] {
+        assert!(
+            res.lines().any(|l| l == *line),
+            "missing {} in {}",
+            line,
+            res
+        );
+    }
+    assert!(res.contains("# TYPE kvs_request_duration_seconds histogram\n"));
+    assert!(res.contains("\nkvs_engine_garbage_ratio 0."));
+
+    assert!(scrape("/other")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // A connection stalled before its request does not block the scrapes.
+    let stalled = TcpStream::connect(metrics_addr)?;
+    assert!(scrape("/metrics")?.starts_with("HTTP/1.1 200 OK\r\n"));
+    drop(stalled);
+
+    Ok(())
+}
+
+// Should serve clients over TLS which trust the CA of the server certificate
+#[test]
+fn tls_connections() -> Result<()> {
+    let addr = "127.0.0.1:4113".parse().unwrap();
+    let cert_dir = TempDir::new().expect("unable to create temporary certificate directory");
+    let ca = generate_certs(cert_dir.path(), "ca");
+    let other_ca = generate_certs(cert_dir.path(), "other-ca");
+    let tls = TlsServerConfig::from_pem_files(&ca.server_cert, &ca.server_key, None)?;
+    let _temp_dir = start_server_with_options(addr, KvsServerOptions::new().tls(Some(tls)))?;
+
+    let tls = TlsClientConfig::from_pem_files(&ca.ca_cert, None)?;
+    let mut client = KvsClientBuilder::new(addr).tls(Some(tls.clone())).build()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // The certificate is valid for both the IP address and the DNS name.
+    let localhost = tls.clone().server_name("localhost")?;
+    let mut client = KvsClientBuilder::new(addr).tls(Some(localhost)).build()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A pool connects with the TLS configuration of its builder.
+    let builder = KvsClientBuilder::new(addr).tls(Some(tls.clone()));
+    let pool = KvsClientPool::with_builder(builder, KvsClientPoolOptions::new())?;
+    pool.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(pool.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let wrong_name = tls.server_name("example.com")?;
+    match KvsClientBuilder::new(addr).tls(Some(wrong_name)).build() {
+        Err(KvsError::Tls(_)) => {}
+        res => panic!("expected TLS error, got {:?}", res.map(|_| ())),
+    }
+    let untrusted = TlsClientConfig::from_pem_files(&other_ca.ca_cert, None)?;
+    match KvsClientBuilder::new(addr).tls(Some(untrusted)).build() {
+        Err(KvsError::Tls(_)) => {}
+        res => panic!("expected TLS error, got {:?}", res.map(|_| ())),
+    }
+    assert!(KvsClient::new(addr).is_err());
+
+    Ok(())
+}
+
+// Should serve only clients with certificates issued by the client CA
+#[test]
+fn mutual_tls() -> Result<()> {
+    let addr = "127.0.0.1:4114".parse().unwrap();
+    let cert_dir = TempDir::new().expect("unable to create temporary certificate directory");
+    let ca = generate_certs(cert_dir.path(), "ca");
+    let other_ca = generate_certs(cert_dir.path(), "other-ca");
+    let tls = TlsServerConfig::from_pem_files(&ca.server_cert, &ca.server_key, Some(&ca.ca_cert))?;
+    let _temp_dir = start_server_with_options(addr, KvsServerOptions::new().tls(Some(tls)))?;
+
+    let tls = TlsClientConfig::from_pem_files(
+        &ca.ca_cert,
+        Some((ca.client_cert.as_path(), ca.client_key.as_path())),
+    )?;
+    let mut client = KvsClientBuilder::new(addr).tls(Some(tls)).build()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let anonymous = TlsClientConfig::from_pem_files(&ca.ca_cert, None)?;
+    match KvsClientBuilder::new(addr).tls(Some(anonymous)).build() {
+        Err(KvsError::Tls(_)) => {}
+        res => panic!("expected TLS error, got {:?}", res.map(|_| ())),
+    }
+    let untrusted = TlsClientConfig::from_pem_files(
+        &ca.ca_cert,
+        Some((
+            other_ca.client_cert.as_path(),
+            other_ca.client_key.as_path(),
+        )),
+    )?;
+    match KvsClientBuilder::new(addr).tls(Some(untrusted)).build() {
+        Err(KvsError::Tls(_)) => {}
+        res => panic!("expected TLS error, got {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Should authenticate clients, and permit requests by the grants of their users
+#[test]
+fn access_control() -> Result<()> {
+    let addr = "127.0.0.1:4115".parse().unwrap();
+    let acl_dir = TempDir::new().expect("unable to create temporary ACL directory");
+    let acl_path = acl_dir.path().join("acl.json");
+    fs::write(
+        &acl_path,
+        r#"{
+            "users": [
+                {
+                    "name": "admin",
+                    "token": "admin-token",
+                    "grants": [{ "prefix": "", "permission": "write" }]
+                },
+                {
+                    "name": "alice",
+                    "token": "alice-token",
+                    "grants": [
+                        { "prefix": "", "permission": "read" },
+                        { "prefix": "alice/", "permission": "write" }
+                    ]
+                }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let acl = Acl::from_file(&acl_path)?;
+    let _temp_dir = start_server_with_options(addr, KvsServerOptions::new().acl(Some(acl)))?;
+
+    let credentials = |user: &str, token: &str| {
+        KvsClientBuilder::new(addr)
+            .credentials(Some(Credentials::new(user.to_owned(), token.to_owned())))
+    };
+    let mut admin = credentials("admin", "admin-token").build()?;
+    admin.set("shared".to_owned(), "value1".to_owned())?;
+
+    let mut alice = credentials("alice", "alice-token").build()?;
+    assert_eq!(alice.get("shared".to_owned())?, Some("value1".to_owned()));
+    alice.set("alice/key".to_owned(), "value2".to_owned())?;
+    alice.remove("alice/key".to_owned())?;
+    match alice.set("shared".to_owned(), "value3".to_owned()) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("expected permission denied, got {:?}", res),
+    }
+    match alice.remove("shared".to_owned()) {
+        Err(KvsError::PermissionDenied(_)) => {}
+        res => panic!("expected permission denied, got {:?}", res),
+    }
+    // The connection is still usable after a denied request.
+    assert_eq!(admin.get("shared".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(alice.get("shared".to_owned())?, Some("value1".to_owned()));
+
+    for builder in [
+        credentials("alice", "wrong-token"),
+        credentials("mallory", "alice-token"),
+        KvsClientBuilder::new(addr),
+    ] {
+        match builder.build() {
+            Err(KvsError::AuthenticationFailed) => {}
+            res => panic!("expected authentication failure, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    // A client without the auth capability is rejected in the handshake it understands.
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&MAGIC)?;
+    write_frame(
+        &mut stream,
+        &Handshake {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        },
+    )?;
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    match read_frame(&mut stream)? {
+        HandshakeResponse::Rejected { .. } => {}
+        res => panic!("expected rejection, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Should serve clients and pools on a Unix domain socket, and remove its file on shutdown
+#[cfg(unix)]
+#[test]
+fn unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let addr = Address::Unix(socket_path.clone());
+    assert_eq!(
+        format!("unix:{}", socket_path.display()).parse::<Address>(),
+        Ok(addr.clone())
+    );
+
+    // A socket file left behind by a server which is gone is replaced.
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+    assert!(socket_path.exists());
+
+    let engine = KvStore::open(temp_dir.path())?;
+    let mut server = KvsServer::new(addr.clone(), engine);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::new(addr.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let pool = KvsClientPool::new(addr.clone())?;
+    for _ in 0..3 {
+        assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    assert_eq!(pool.open_connections(), 1);
+
+    // A second server does not take over the socket of a running one.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(other_dir.path())?;
+    assert!(KvsServer::new(addr.clone(), engine).start().is_err());
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(!socket_path.exists());
+
+    Ok(())
+}
+
+// Should map error codes of responses back into specific errors
+#[test]
+fn error_codes() -> Result<()> {
+    let addr = "127.0.0.1:4103".parse().unwrap();
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::new(addr)?;
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    let read_only_addr: SocketAddr = "127.0.0.1:4104".parse().unwrap();
+    let read_only_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open_read_only(read_only_dir.path())?;
+    thread::spawn(move || KvsServer::new(read_only_addr, engine).start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::new(read_only_addr)?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Should serve the RESP commands of Redis
+#[test]
+fn resp_commands() -> Result<()> {
+    let addr = "127.0.0.1:4105".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || RespServer::new(addr, engine).start());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut resp = |command: &[&str], expected: &str| -> Result<()> {
+        let mut request = format!("*{}\r\n", command.len());
+        for arg in command {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        stream.write_all(request.as_bytes())?;
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply)?;
+        assert_eq!(String::from_utf8_lossy(&reply), expected);
+        Ok(())
+    };
+
+    resp(&["PING"], "+PONG\r\n")?;
+    resp(&["SET", "key1", "value1"], "+OK\r\n")?;
+    resp(&["set", "key2", "value2"], "+OK\r\n")?;
+    resp(&["SET", "other", ""], "+OK\r\n")?;
+    resp(&["GET", "key1"], "$6\r\nvalue1\r\n")?;
+    resp(&["GET", "key3"], "$-1\r\n")?;
+    resp(&["EXISTS", "key1", "key3", "other"], ":2\r\n")?;
+    resp(
+        &["MGET", "key1", "key3", "other"],
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$0\r\n\r\n",
+    )?;
+    resp(&["KEYS", "key[0-9]"], "*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n")?;
+    resp(
+        &["KEYS", "*"],
+        "*3\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$5\r\nother\r\n",
+    )?;
+    resp(&["KEYS", "k?y[^2]"], "*1\r\n$4\r\nkey1\r\n")?;
+    resp(&["KEYS", "*e*r"], "*1\r\n$5\r\nother\r\n")?;
+    // Many stars on a long key do not backtrack exponentially.
+    let long_key = "a".repeat(100);
+    resp(&["SET", &long_key, ""], "+OK\r\n")?;
+    resp(&["KEYS", "*a*a*a*a*a*a*a*a*a*a*a*a*b"], "*0\r\n")?;
+    resp(&["DEL", &long_key], ":1\r\n")?;
+    resp(&["DEL", "key1", "key3"], ":1\r\n")?;
+    resp(&["GET", "key1"], "$-1\r\n")?;
+    resp(
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    )?;
+    resp(&["FLUSHALL"], "-ERR unknown command 'flushall'\r\n")?;
+
+    // Another connection is served while the first one is open.
+    let mut other = TcpStream::connect(addr)?;
+    other.set_read_timeout(Some(Duration::from_secs(5)))?;
+    other.write_all(b"*1\r\n$4\r\nPING\r\n")?;
+    let mut reply = [0; 7];
+    other.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"+PONG\r\n");
+
+    // Inline commands are served as well.
+    stream.write_all(b"PING hello\r\n")?;
+    let mut reply = [0; 11];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"$5\r\nhello\r\n");
+
+    Ok(())
+}
+
+// Should serve the HTTP/JSON gateway with proper status codes
+#[test]
+fn http_gateway() -> Result<()> {
+    let addr = "127.0.0.1:4106".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || HttpServer::new(addr, engine).start());
+    thread::sleep(Duration::from_millis(100));
+
+    let http = |method: &str, target: &str, body: &str| -> Result<(String, String)> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res)?;
+        let status = res.split(' ').nth(1).unwrap_or_default().to_owned();
+        let body = res
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_owned())
+            .unwrap_or_default();
+        Ok((status, body))
+    };
+
+    // A connection stalled in the middle of a large body does not block the others.
+    let mut stalled = TcpStream::connect(addr)?;
+    write!(
+        stalled,
+        "PUT /kv/key1 HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n{{"
+    )?;
+
+    assert_eq!(
+        http("GET", "/health", "")?,
+        ("200".to_owned(), r#"{"status":"ok"}"#.to_owned())
+    );
+    assert_eq!(http("PUT", "/kv/key1", r#"{"value":"value1"}"#)?.0, "204");
+    assert_eq!(
+        http("PUT", "/kv/key%202", r#"{"value":"value2"}"#)?.0,
+        "204"
+    );
+    assert_eq!(http("PUT", "/kv/other", r#"{"value":"value3"}"#)?.0, "204");
+    assert_eq!(
+        http("GET", "/kv/key1", "")?,
+        (
+            "200".to_owned(),
+            r#"{"key":"key1","value":"value1"}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http("GET", "/kv?start=key&end=l", "")?,
+        (
+            "200".to_owned(),
+            r#"[{"key":"key 2","value":"value2"},{"key":"key1","value":"value1"}]"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http("GET", "/kv?limit=1", "")?.1,
+        r#"[{"key":"key 2","value":"value2"}]"#
+    );
+    // `+` is literal in a path, and only a space in a query.
+    assert_eq!(http("PUT", "/kv/a+b", r#"{"value":"plus"}"#)?.0, "204");
+    assert_eq!(
+        http("GET", "/kv/a%2Bb", "")?,
+        (
+            "200".to_owned(),
+            r#"{"key":"a+b","value":"plus"}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http("GET", "/kv?start=a+&end=a%2Bc", "")?.1,
+        r#"[{"key":"a+b","value":"plus"}]"#
+    );
+    assert_eq!(http("DELETE", "/kv/a+b", "")?.0, "204");
+    assert_eq!(http("DELETE", "/kv/key1", "")?.0, "204");
+    assert_eq!(
+        http("GET", "/kv/key1", "")?,
+        (
+            "404".to_owned(),
+            r#"{"code":"KeyNotFound","message":"Key not found"}"#.to_owned()
+        )
+    );
+    assert_eq!(http("DELETE", "/kv/key1", "")?.0, "404");
+    assert_eq!(http("PUT", "/kv/key1", "value1")?.0, "400");
+    assert_eq!(http("POST", "/kv/key1", "")?.0, "405");
+    assert_eq!(http("GET", "/unknown", "")?.0, "404");
+    drop(stalled);
+
+    Ok(())
+}
+
+// Should reject a client without a common protocol version
+#[test]
+fn handshake_version_mismatch() -> Result<()> {
+    let addr = "127.0.0.1:4101".parse().unwrap();
+    let _temp_dir = start_server(addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&MAGIC)?;
+    write_frame(
+        &mut stream,
+        &Handshake {
+            min_version: PROTOCOL_VERSION + 1,
+            max_version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+        },
+    )?;
+
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    assert_eq!(magic, MAGIC);
+    match read_frame(&mut stream)? {
+        HandshakeResponse::Rejected { .. } => {}
+        res => panic!("unexpected handshake response {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Should close the connection of a client without the framed protocol
+#[test]
+fn unframed_client() -> Result<()> {
+    let addr = "127.0.0.1:4102".parse().unwrap();
+    let _temp_dir = start_server(addr)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let request = Request::Get {
+        key: "key1".to_owned(),
+    };
+    // The connection may be closed or reset before all the bytes are written,
+    // since the server closes it after reading the magic bytes.
+    let _ = stream.write_all(&bincode::serialize(&request)?);
+    let mut buf = Vec::new();
+    if stream.read_to_end(&mut buf).is_ok() {
+        assert!(buf.is_empty());
+    }
+
+    Ok(())
+}
+
+fn start_server(addr: SocketAddr) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || KvsServer::new(addr, engine).start());
+    thread::sleep(Duration::from_millis(100));
+    Ok(temp_dir)
+}
+
+fn start_server_with_options(addr: SocketAddr, options: KvsServerOptions) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::with_options(addr, KvStore::open(temp_dir.path())?, options);
+    thread::spawn(move || server.start());
+    thread::sleep(Duration::from_millis(100));
+    Ok(temp_dir)
+}
+
+fn start_async_server_with_options(addr: SocketAddr, options: KvsServerOptions) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = AsyncKvsServer::with_options(addr, KvStore::open(temp_dir.path())?, options);
+    thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(server.start())
+    });
+    thread::sleep(Duration::from_millis(100));
+    Ok(temp_dir)
+}
+
+/// The PEM files of a CA, and of a server and a client certificate it issues.
+struct Certs {
+    ca_cert: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+fn generate_certs(dir: &Path, name: &str) -> Certs {
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+    let issue = |sans: Vec<String>, prefix: &str| {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(sans)
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        let cert_path = dir.join(format!("{}-{}.pem", name, prefix));
+        let key_path = dir.join(format!("{}-{}-key.pem", name, prefix));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    };
+    let (server_cert, server_key) = issue(
+        vec!["127.0.0.1".to_owned(), "localhost".to_owned()],
+        "server",
+    );
+    let (client_cert, client_key) = issue(vec!["client".to_owned()], "client");
+
+    let ca_cert = dir.join(format!("{}.pem", name));
+    fs::write(&ca_cert, ca.pem()).unwrap();
+    Certs {
+        ca_cert,
+        server_cert,
+        server_key,
+        client_cert,
+        client_key,
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, msg: &Handshake) -> Result<()> {
+    let payload = bincode::serialize(msg)?;
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(&payload)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<HandshakeResponse> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(bincode::deserialize(&payload)?)
+}